@@ -0,0 +1,464 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+
+use crate::{
+    Choice, FunctionCall, Message, OpenRouterRequest, OpenRouterResponse, ToolCall, Usage,
+};
+
+/// Base URL of Anthropic's API.
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// Version of the messages API the requests are written for, sent in the `anthropic-version`
+/// header.
+pub const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic requires a maximum number of tokens on every request; this is used without
+/// --max-tokens.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+// Anthropic's messages API (/v1/messages) is shaped differently from the OpenAI one: the system
+// prompt is a top-level field, the content of a message is a list of blocks, tool calls are
+// "tool_use" blocks of the assistant, and their results are "tool_result" blocks of a user
+// message rather than messages of their own.
+
+#[derive(serde::Serialize)]
+pub struct AnthropicRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<AnthropicTool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct Metadata<'a> {
+    user_id: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct AnthropicTool<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    input_schema: serde_json::Value,
+}
+
+#[derive(serde::Serialize)]
+struct AnthropicMessage {
+    role: &'static str, // "user" or "assistant"
+    content: Vec<Block>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+    // blocks of other types (e.g. redacted thinking) are of no use here
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+#[derive(serde::Deserialize)]
+struct AnthropicResponse {
+    id: Option<String>,
+    model: Option<String>,
+    #[serde(default)]
+    content: Vec<Block>,
+    stop_reason: Option<String>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+/// Turns an image URL of a message into an image source; data URLs are sent as base64 data.
+fn image_source(url: &str) -> ImageSource {
+    if let Some(data_url) = url.strip_prefix("data:")
+        && let Some((media_type, data)) = data_url.split_once(";base64,")
+    {
+        return ImageSource::Base64 {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        };
+    }
+    ImageSource::Url {
+        url: url.to_string(),
+    }
+}
+
+/// Returns the content blocks of a message, and the role it has for Anthropic.
+fn to_blocks(message: &Message) -> (&'static str, Vec<Block>) {
+    let text = message.content.clone().unwrap_or_default();
+    if message.role == "tool" {
+        let block = Block::ToolResult {
+            tool_use_id: message.tool_call_id.clone().unwrap_or_default(),
+            content: text,
+        };
+        return ("user", vec![block]);
+    }
+
+    let mut blocks = Vec::new();
+    // empty text blocks are rejected, and assistant messages with tool calls often have no text
+    if !text.is_empty() {
+        blocks.push(Block::Text { text });
+    }
+    for url in &message.images {
+        blocks.push(Block::Image {
+            source: image_source(url),
+        });
+    }
+    for tool_call in message.tool_calls.iter().flatten() {
+        blocks.push(Block::ToolUse {
+            id: tool_call.id.clone(),
+            name: tool_call.function.name.clone(),
+            // the input has to be an object
+            input: tool_call.function.arguments_object(),
+        });
+    }
+
+    let role = if message.role == "assistant" {
+        "assistant"
+    } else {
+        "user"
+    };
+    (role, blocks)
+}
+
+/// Translates a request into the shape of Anthropic's messages API. Streaming is not supported,
+/// the response is always received at once.
+pub fn to_request(request_body: &OpenRouterRequest) -> AnthropicRequest<'_> {
+    let mut system = Vec::new();
+    let mut messages: Vec<AnthropicMessage> = Vec::new();
+    for message in &request_body.messages {
+        if message.role == "system" {
+            system.extend(message.content.clone());
+            continue;
+        }
+
+        let (role, mut blocks) = to_blocks(message);
+        // the roles have to alternate, so e.g. the results of several tool calls go into one user
+        // message
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.append(&mut blocks),
+            _ => messages.push(AnthropicMessage {
+                role,
+                content: blocks,
+            }),
+        }
+    }
+
+    let tools = request_body
+        .tools
+        .iter()
+        .flatten()
+        .map(|definition| AnthropicTool {
+            name: &definition.function.name,
+            description: definition.function.description.as_deref(),
+            input_schema: definition
+                .function
+                .parameters
+                .clone()
+                .unwrap_or_else(|| serde_json::json!({"type": "object"})),
+        })
+        .collect();
+
+    AnthropicRequest {
+        model: &request_body.model,
+        system: (!system.is_empty()).then(|| system.join("\n\n")),
+        messages,
+        max_tokens: request_body.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        tools,
+        temperature: request_body.temperature,
+        top_p: request_body.top_p,
+        top_k: request_body.top_k,
+        stop_sequences: &request_body.stop,
+        metadata: request_body
+            .user
+            .as_deref()
+            .map(|user_id| Metadata { user_id }),
+    }
+}
+
+/// Parses a response of Anthropic's messages API into the common response shape.
+pub fn parse_response(response_text: &str) -> Result<OpenRouterResponse> {
+    let response: AnthropicResponse = serde_json::from_str(response_text).with_context(|| {
+        format!(
+            "Failed to parse JSON of the API request response; Response body was: {}",
+            response_text
+        )
+    })?;
+
+    let mut content = Vec::new();
+    let mut reasoning = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in response.content {
+        match block {
+            Block::Text { text } => content.push(text),
+            Block::Thinking { thinking } => reasoning.push(thinking),
+            Block::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                id,
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name,
+                    arguments: input.to_string(),
+                },
+            }),
+            _ => {}
+        }
+    }
+
+    let message = Message {
+        role: "assistant".to_string(),
+        content: (!content.is_empty() || tool_calls.is_empty()).then(|| content.concat()),
+        images: Vec::new(),
+        tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+        tool_call_id: None,
+        reasoning: (!reasoning.is_empty()).then(|| reasoning.join("\n\n")),
+    };
+
+    // the finish reasons are translated, so that e.g. cut off answers are still noticed
+    let finish_reason = response.stop_reason.map(|reason| {
+        match reason.as_str() {
+            "end_turn" | "stop_sequence" => "stop",
+            "max_tokens" => "length",
+            "tool_use" => "tool_calls",
+            other => other,
+        }
+        .to_string()
+    });
+
+    let usage = response.usage.map(|usage| Usage {
+        prompt_tokens: usage.input_tokens,
+        completion_tokens: usage.output_tokens,
+        total_tokens: usage.input_tokens + usage.output_tokens,
+    });
+
+    Ok(OpenRouterResponse {
+        id: response.id,
+        model: response.model,
+        choices: vec![Choice {
+            message,
+            finish_reason,
+        }],
+        usage,
+        error: None,
+        seed: None,
+        system_fingerprint: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool_call(id: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: "echo".to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    fn tool_result(id: &str, content: &str) -> Message {
+        Message {
+            tool_call_id: Some(id.to_string()),
+            ..Message::new("tool", content.to_string())
+        }
+    }
+
+    fn translate(messages: Vec<Message>) -> serde_json::Value {
+        let request_body = OpenRouterRequest {
+            model: "claude".to_string(),
+            messages,
+            ..Default::default()
+        };
+        serde_json::to_value(to_request(&request_body)).unwrap()
+    }
+
+    #[test]
+    fn system_messages_become_the_system_field() {
+        let request = translate(vec![
+            Message::new("system", "Be brief.".to_string()),
+            Message::new("system", "Be kind.".to_string()),
+            Message::new("user", "Hi".to_string()),
+        ]);
+        assert_eq!(request["system"], "Be brief.\n\nBe kind.");
+        assert_eq!(request["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(
+            request["messages"],
+            serde_json::json!([{"role": "user", "content": [{"type": "text", "text": "Hi"}]}])
+        );
+    }
+
+    #[test]
+    fn tool_results_share_one_user_message() {
+        let assistant = Message {
+            role: "assistant".to_string(),
+            content: None,
+            images: Vec::new(),
+            tool_calls: Some(vec![tool_call("a", "{\"x\":1}"), tool_call("b", "{}")]),
+            tool_call_id: None,
+            reasoning: None,
+        };
+        let request = translate(vec![
+            Message::new("user", "Hi".to_string()),
+            assistant,
+            tool_result("a", "one"),
+            tool_result("b", "two"),
+        ]);
+
+        let messages = request["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[1]["content"],
+            serde_json::json!([
+                {"type": "tool_use", "id": "a", "name": "echo", "input": {"x": 1}},
+                {"type": "tool_use", "id": "b", "name": "echo", "input": {}},
+            ])
+        );
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(
+            messages[2]["content"],
+            serde_json::json!([
+                {"type": "tool_result", "tool_use_id": "a", "content": "one"},
+                {"type": "tool_result", "tool_use_id": "b", "content": "two"},
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid_arguments_are_sent_as_an_object() {
+        for (arguments, input) in [
+            ("{\"x\":", serde_json::json!({"arguments": "{\"x\":"})),
+            ("[1]", serde_json::json!({"arguments": "[1]"})),
+            ("", serde_json::json!({})),
+        ] {
+            let assistant = Message {
+                tool_calls: Some(vec![tool_call("a", arguments)]),
+                ..Message::new("assistant", String::new())
+            };
+            let request = translate(vec![Message::new("user", "Hi".to_string()), assistant]);
+            assert_eq!(request["messages"][1]["content"][0]["input"], input);
+        }
+    }
+
+    #[test]
+    fn data_urls_are_sent_as_base64() {
+        let message = Message {
+            images: vec![
+                "data:image/png;base64,AAAA".to_string(),
+                "https://example.com/a.png".to_string(),
+            ],
+            ..Message::new("user", "Look".to_string())
+        };
+        let request = translate(vec![message]);
+        assert_eq!(
+            request["messages"][0]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "Look"},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}},
+                {"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}},
+            ])
+        );
+    }
+
+    #[test]
+    fn responses_are_translated() {
+        let response = parse_response(
+            r#"{
+                "id": "msg_1",
+                "model": "claude",
+                "content": [
+                    {"type": "thinking", "thinking": "hmm", "signature": "x"},
+                    {"type": "redacted_thinking", "data": "x"},
+                    {"type": "text", "text": "Let me check."},
+                    {"type": "tool_use", "id": "a", "name": "echo", "input": {"x": 1}}
+                ],
+                "stop_reason": "tool_use",
+                "usage": {"input_tokens": 10, "output_tokens": 5}
+            }"#,
+        )
+        .unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(choice.message.content.as_deref(), Some("Let me check."));
+        assert_eq!(choice.message.reasoning.as_deref(), Some("hmm"));
+        let tool_calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "a");
+        assert_eq!(tool_calls[0].function.arguments, "{\"x\":1}");
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    #[test]
+    fn cut_off_answers_are_noticed() {
+        let response = parse_response(
+            r#"{"content": [{"type": "text", "text": "Once"}], "stop_reason": "max_tokens"}"#,
+        )
+        .unwrap();
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("length"));
+        assert!(response.choices[0].message.tool_calls.is_none());
+    }
+}
@@ -0,0 +1,61 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::fs;
+use std::path::Path;
+
+use crate::transcript::fence_for;
+
+/// Reads an --attach file and wraps it in a code fence labeled with its path. Binary files (that
+/// aren't UTF-8, or contain NUL bytes) are an error, unless `base64` is set, which embeds them
+/// base64-encoded instead.
+pub fn load_attachment(path: &Path, base64: bool) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    let (label, content) = match String::from_utf8(bytes) {
+        Ok(text) if !text.contains('\0') => (path.display().to_string(), text),
+        Ok(text) if base64 => (
+            format!("{} (base64)", path.display()),
+            BASE64.encode(text.into_bytes()),
+        ),
+        Err(e) if base64 => (
+            format!("{} (base64)", path.display()),
+            BASE64.encode(e.into_bytes()),
+        ),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "{} is a binary file (use --attach-base64 to attach it base64-encoded)",
+                path.display()
+            ));
+        }
+    };
+
+    let fence = fence_for(&content);
+
+    let newline = if content.ends_with('\n') { "" } else { "\n" };
+    Ok(format!(
+        "{}{}\n{}{}{}",
+        fence, label, content, newline, fence
+    ))
+}
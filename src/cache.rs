@@ -0,0 +1,76 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// The on-disk cache of responses of --cache-dir. Every response is a file named by the hash of
+/// the request it answered.
+pub struct Cache {
+    pub dir: PathBuf,
+    pub ttl: Option<Duration>, // cached responses never expire without one
+}
+
+impl Cache {
+    /// Returns the key of a request: the SHA-256 of the URL and the serialized body, in hex.
+    pub fn key(url: &str, body: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        // the URL can't contain a NUL, so no URL and body split up differently give the same key
+        hasher.update([0]);
+        hasher.update(body);
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// Returns the cached response for the key, unless there is none or it is older than the TTL.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let path = self.path(key);
+
+        if let Some(ttl) = self.ttl {
+            let age = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+            if age > ttl {
+                return None;
+            }
+        }
+        fs::read_to_string(&path).ok()
+    }
+
+    pub fn put(&self, key: &str, response: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create directory {}", self.dir.display()))?;
+        let path = self.path(key);
+        fs::write(&path, response).with_context(|| format!("Could not write {}", path.display()))
+    }
+}
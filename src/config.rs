@@ -0,0 +1,265 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::{Args, Provider, get_config_dir};
+
+/// Defaults for the command line options, read from `~/.config/rapidllm/config.toml`. Every key
+/// is named like the field of the option it sets, e.g. `character_limit = 4096`.
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    model: Option<String>,
+    fallback_model: Option<Vec<String>>,
+    provider: Option<Provider>,
+    base_url: Option<String>,
+    chat_path: Option<String>,
+    character_limit: Option<usize>,
+    token_limit: Option<usize>,
+    max_prompt_chars: Option<usize>,
+    output_limit: Option<usize>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    min_p: Option<f32>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    timeout: Option<u64>,
+    proxy: Option<String>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    max_rate_limit_wait: Option<u64>,
+    deadline: Option<u64>,
+    cache_dir: Option<PathBuf>,
+    cache_ttl: Option<u64>,
+    max_iterations: Option<usize>,
+    max_continuations: Option<usize>,
+    tool_timeout: Option<u64>,
+    tool_output_limit: Option<usize>,
+    tools_dir: Option<Vec<PathBuf>>,
+    referer: Option<String>,
+    title: Option<String>,
+    user: Option<String>,
+    #[serde(default)]
+    models: HashMap<String, ModelProfile>,
+}
+
+/// Defaults for one model, from a `[models."<model>"]` section. They apply when that model is
+/// selected, and take precedence over the global defaults.
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ModelProfile {
+    token_limit: Option<usize>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    min_p: Option<f32>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+}
+
+/// Reads the config file. A missing file is the same as an empty one, and so is a missing config
+/// directory (e.g. HOME isn't set in a container), since every option has a default anyway.
+pub fn load_config() -> Result<Config> {
+    read_config(get_config_dir().ok().as_deref())
+}
+
+fn read_config(dir: Option<&Path>) -> Result<Config> {
+    let Some(dir) = dir else {
+        return Ok(Config::default());
+    };
+    let path = dir.join("config.toml");
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).context(format!("Could not open file {}", path.display())),
+    };
+
+    toml::from_str(&content).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+impl Config {
+    /// Applies the config to the parsed arguments. Options given on the command line (or in their
+    /// environment variable) always take precedence, only the ones left at their clap defaults (or
+    /// unset) are overridden. The profile of the selected model is applied last, so it wins over the
+    /// global defaults.
+    pub fn apply(mut self, args: &mut Args, matches: &ArgMatches) {
+        let from_command_line = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = self.$field {
+                    if !from_command_line(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            };
+            ($field:ident, optional) => {
+                apply!(self, $field, optional);
+            };
+            ($source:ident, $field:ident, optional) => {
+                if $source.$field.is_some() && !from_command_line(stringify!($field)) {
+                    args.$field = $source.$field;
+                }
+            };
+        }
+
+        apply!(model);
+        apply!(fallback_model);
+        apply!(provider);
+        apply!(base_url, optional);
+        apply!(chat_path, optional);
+        apply!(character_limit);
+        apply!(token_limit, optional);
+        apply!(max_prompt_chars, optional);
+        apply!(output_limit, optional);
+        apply!(max_tokens, optional);
+        apply!(temperature, optional);
+        apply!(top_p, optional);
+        apply!(top_k, optional);
+        apply!(min_p, optional);
+        apply!(presence_penalty, optional);
+        apply!(frequency_penalty, optional);
+        apply!(timeout);
+        apply!(proxy, optional);
+        apply!(retries);
+        apply!(retry_delay);
+        apply!(max_rate_limit_wait);
+        apply!(deadline, optional);
+        apply!(cache_dir, optional);
+        apply!(cache_ttl, optional);
+        apply!(max_iterations);
+        apply!(max_continuations);
+        apply!(tool_timeout, optional);
+        apply!(tool_output_limit, optional);
+        apply!(tools_dir);
+        apply!(referer, optional);
+        apply!(title, optional);
+        apply!(user, optional);
+
+        if let Some(profile) = self.models.remove(&args.model) {
+            apply!(profile, token_limit, optional);
+            apply!(profile, max_tokens, optional);
+            apply!(profile, temperature, optional);
+            apply!(profile, top_p, optional);
+            apply!(profile, top_k, optional);
+            apply!(profile, min_p, optional);
+            apply!(profile, presence_penalty, optional);
+            apply!(profile, frequency_penalty, optional);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn parse(config: &str, command_line: &[&str]) -> Args {
+        let config: Config = toml::from_str(config).unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(std::iter::once("rlm").chain(command_line.iter().copied()))
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+        args
+    }
+
+    #[test]
+    fn missing_config_is_empty() {
+        let dir = std::env::temp_dir().join(format!("rlm-config-test-{}", std::process::id()));
+        let config = read_config(Some(&dir)).unwrap();
+        assert!(config.model.is_none() && config.models.is_empty());
+    }
+
+    #[test]
+    fn missing_config_dir_is_empty() {
+        let config = read_config(None).unwrap();
+        assert!(config.model.is_none() && config.models.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("modle = \"x\"").is_err());
+    }
+
+    #[test]
+    fn config_overrides_defaults() {
+        let args = parse("model = \"openai/gpt-4o\"\ncharacter_limit = 100", &[]);
+        assert_eq!(args.model, "openai/gpt-4o");
+        assert_eq!(args.character_limit, 100);
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let args = parse(
+            "model = \"openai/gpt-4o\"\ntemperature = 0.5",
+            &["-m", "x/y", "--temperature", "1"],
+        );
+        assert_eq!(args.model, "x/y");
+        assert_eq!(args.temperature, Some(1.0));
+    }
+
+    #[test]
+    fn profile_overrides_global_defaults() {
+        let config = "temperature = 0.2\nmax_tokens = 10\n\n[models.\"a/b\"]\ntemperature = 0.7";
+        let args = parse(config, &["-m", "a/b"]);
+        assert_eq!(args.temperature, Some(0.7));
+        assert_eq!(args.max_tokens, Some(10));
+    }
+
+    #[test]
+    fn profile_of_configured_model_applies() {
+        let args = parse("model = \"a/b\"\n[models.\"a/b\"]\ntop_k = 5", &[]);
+        assert_eq!(args.top_k, Some(5));
+    }
+
+    #[test]
+    fn profile_of_other_model_is_ignored() {
+        let args = parse(
+            "temperature = 0.2\n[models.\"a/b\"]\ntemperature = 0.7",
+            &["-m", "c/d"],
+        );
+        assert_eq!(args.temperature, Some(0.2));
+    }
+
+    #[test]
+    fn command_line_overrides_profile() {
+        let config = "[models.\"a/b\"]\ntemperature = 0.7";
+        let args = parse(config, &["-m", "a/b", "--temperature", "1"]);
+        assert_eq!(args.temperature, Some(1.0));
+    }
+}
@@ -0,0 +1,249 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use std::error::Error;
+use std::fmt;
+
+use reqwest::StatusCode;
+
+use crate::StatusError;
+
+/// The classes of failures that get an exit status of their own, so that scripts can tell them
+/// apart without parsing the error message. Anything else exits with 1.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Failure {
+    /// The config file, an option or the API key is missing or invalid, or the API rejected the
+    /// key.
+    Config,
+    /// The API could not be reached, or the connection broke off.
+    Network,
+    /// The API rejected the request (4xx).
+    Client,
+    /// The API failed to handle the request (5xx).
+    Server,
+    /// A tool could not be loaded or run, or the model called it wrongly.
+    Tool,
+    /// The input exceeded one of the limits, or the run its deadline.
+    Limit,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 4,
+            Failure::Network => 5,
+            Failure::Client => 6,
+            Failure::Server => 7,
+            Failure::Tool => 8,
+            Failure::Limit => 9,
+        }
+    }
+}
+
+// an error tagged with its class. It displays as the error itself, so tagging an error doesn't
+// change what is printed.
+#[derive(Debug)]
+struct Classified {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for Classified {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// Tags the error of a result with its class, like `anyhow::Context` adds context.
+pub trait Classify<T> {
+    fn classify(self, failure: Failure) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, failure: Failure) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            Classified {
+                failure,
+                error: error.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// Returns the class of an error that ended the run. Errors of the API and the HTTP client are
+/// classified by their type, everything else by its tag.
+fn classify(error: &anyhow::Error) -> Option<Failure> {
+    if let Some(classified) = error.downcast_ref::<Classified>() {
+        Some(classified.failure)
+    } else if let Some(status_error) = error.downcast_ref::<StatusError>() {
+        Some(match status_error.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Failure::Config,
+            status if status.is_server_error() => Failure::Server,
+            _ => Failure::Client,
+        })
+    } else if error.downcast_ref::<reqwest::Error>().is_some() {
+        Some(Failure::Network)
+    } else {
+        None
+    }
+}
+
+/// Returns the exit status for an error that ended the run.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    classify(error).map_or(1, Failure::exit_code)
+}
+
+#[derive(serde::Serialize)]
+struct JsonError<'a> {
+    error: String,
+    kind: Option<Failure>, // null for the failures without an exit status of their own
+    details: JsonErrorDetails<'a>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonErrorDetails<'a> {
+    causes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>, // parsed when it is JSON, a string otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+/// Formats an error that ended the run for --json-errors, as a JSON object with the message, the
+/// class, and the details: the causes, and the HTTP status and response body of API errors.
+pub fn to_json(error: &anyhow::Error) -> String {
+    let status_error = error
+        .downcast_ref::<StatusError>()
+        .or_else(|| error.chain().find_map(|e| e.downcast_ref::<StatusError>()));
+
+    let output = JsonError {
+        error: error.to_string(),
+        kind: classify(error),
+        details: JsonErrorDetails {
+            causes: error.chain().skip(1).map(ToString::to_string).collect(),
+            status: status_error.map(|e| e.status.as_u16()),
+            body: status_error.map(|e| {
+                serde_json::from_str(&e.body)
+                    .unwrap_or_else(|_| serde_json::Value::String(e.body.clone()))
+            }),
+            request_id: status_error.and_then(|e| e.request_id.as_deref()),
+        },
+    };
+    // there is nothing left to report a failure to serialize to
+    serde_json::to_string(&output).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn status_error(status: u16, body: &str) -> anyhow::Error {
+        StatusError {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+            request_id: None,
+        }
+        .into()
+    }
+
+    #[test]
+    fn unclassified_errors_exit_with_1() {
+        assert_eq!(exit_code(&anyhow::anyhow!("oops")), 1);
+    }
+
+    #[test]
+    fn tags_survive_context() {
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("too long"))
+            .classify(Failure::Limit)
+            .context("Could not read the prompt");
+        let error = result.unwrap_err();
+        assert_eq!(exit_code(&error), 9);
+        // the tag doesn't change the message, nor the chain of causes
+        assert_eq!(
+            format!("{:#}", error),
+            "Could not read the prompt: too long"
+        );
+    }
+
+    #[test]
+    fn statuses_are_classified() {
+        assert_eq!(exit_code(&status_error(401, "")), 4);
+        assert_eq!(exit_code(&status_error(403, "")), 4);
+        assert_eq!(exit_code(&status_error(400, "")), 6);
+        assert_eq!(exit_code(&status_error(429, "")), 6);
+        assert_eq!(exit_code(&status_error(502, "")), 7);
+    }
+
+    #[test]
+    fn tags_win_over_statuses() {
+        let result: anyhow::Result<()> = Err(status_error(500, "")).classify(Failure::Tool);
+        assert_eq!(exit_code(&result.unwrap_err()), 8);
+    }
+
+    #[test]
+    fn json_of_a_plain_error() {
+        let error = anyhow::anyhow!("no such file").context("Could not read the prompt");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&error)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "Could not read the prompt",
+                "kind": null,
+                "details": {"causes": ["no such file"]},
+            })
+        );
+    }
+
+    #[test]
+    fn json_of_an_api_error() {
+        let error = StatusError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            body: "{\"error\":{\"message\":\"slow down\"}}".to_string(),
+            request_id: Some("req-1".to_string()),
+        };
+        let result: anyhow::Result<()> = Err(error.into());
+        let error = result.context("Request failed").unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&to_json(&error)).unwrap();
+        assert_eq!(json["kind"], "client");
+        assert_eq!(json["details"]["status"], 429);
+        assert_eq!(json["details"]["body"]["error"]["message"], "slow down");
+        assert_eq!(json["details"]["request_id"], "req-1");
+    }
+
+    #[test]
+    fn json_keeps_bodies_that_are_not_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json(&status_error(502, "Bad Gateway"))).unwrap();
+        assert_eq!(json["kind"], "server");
+        assert_eq!(json["details"]["body"], "Bad Gateway");
+    }
+}
@@ -0,0 +1,59 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::fs;
+use std::path::Path;
+
+/// Turns an --image argument into the URL sent to the API. URLs are passed through as they are,
+/// local files are embedded as base64 data URLs.
+pub fn load_image(image: &str) -> Result<String> {
+    if image.starts_with("http://") || image.starts_with("https://") {
+        return Ok(image.to_string());
+    }
+
+    let path = Path::new(image);
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let mime_type = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Unsupported image type of {} (expected png, jpeg, gif or webp)",
+                path.display()
+            ));
+        }
+    };
+
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(format!(
+        "data:{};base64,{}",
+        mime_type,
+        BASE64.encode(bytes)
+    ))
+}
@@ -0,0 +1,66 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+/// Whether some part of the run is waiting for Ctrl-C itself, to save the conversation first.
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: Notify = Notify::const_new();
+
+/// Installs the one Ctrl-C handler of the process. While a completion (or the read of an
+/// interactive message) is in progress, it is up to that to handle Ctrl-C; anywhere else, rlm
+/// exits with 130 (128 + SIGINT) right away, like a process killed by the signal.
+pub fn install() {
+    tokio::spawn(async {
+        // if the handler can't be installed, Ctrl-C keeps its default of killing the process
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if CLAIMED.load(Ordering::SeqCst) {
+                INTERRUPTED.notify_one();
+            } else {
+                io::stdout().flush().ok();
+                std::process::exit(130);
+            }
+        }
+    });
+}
+
+/// Takes over Ctrl-C until the returned guard is dropped.
+pub fn claim() -> Claim {
+    CLAIMED.store(true, Ordering::SeqCst);
+    Claim
+}
+
+pub struct Claim;
+
+impl Claim {
+    /// Waits until Ctrl-C is pressed.
+    pub async fn interrupted(&self) {
+        INTERRUPTED.notified().await;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        CLAIMED.store(false, Ordering::SeqCst);
+    }
+}
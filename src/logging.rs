@@ -0,0 +1,89 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use log::{Level, LevelFilter};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::Args;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the logger: messages go to stderr in the same plain format `--verbose` always used, or
+/// to the --log-file with timestamps and levels. --log-level takes precedence over --verbose,
+/// which means debug, and --quiet, which means error; by default, only warnings and errors are
+/// logged.
+pub fn init(args: &Args) -> Result<()> {
+    // with --watch, every run sets it up again, with the same options
+    if INITIALIZED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let level = args.log_level.unwrap_or(if args.verbose {
+        LevelFilter::Debug
+    } else if args.quiet {
+        LevelFilter::Error
+    } else {
+        LevelFilter::Warn
+    });
+
+    let mut builder = env_logger::Builder::new();
+    // the HTTP stack logs a lot at debug level, so it only gets to speak up with trace
+    builder
+        .filter_level(if level == LevelFilter::Trace {
+            LevelFilter::Trace
+        } else {
+            level.min(LevelFilter::Warn)
+        })
+        .filter_module("rlm", level);
+
+    match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Could not open file {}", path.display()))?;
+            builder
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .format(|buf, record| {
+                    writeln!(
+                        buf,
+                        "{} {} {}",
+                        buf.timestamp(),
+                        record.level(),
+                        record.args()
+                    )
+                });
+        }
+        None => {
+            builder.format(|buf, record| match record.level() {
+                Level::Error => writeln!(buf, "Error: {}", record.args()),
+                Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+                _ => writeln!(buf, "{}", record.args()),
+            });
+        }
+    }
+
+    builder.try_init().context("Could not set up logging")
+}
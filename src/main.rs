@@ -19,93 +19,847 @@ General Public License along with this program. If not,
 see <https://www.gnu.org/licenses/>.
 */
 
+mod anthropic;
+mod attachments;
+mod cache;
+mod config;
+mod failure;
+mod images;
+mod interrupt;
+mod logging;
+mod models;
+mod ollama;
+mod postprocess;
+mod session;
+mod stream;
+mod template;
+mod tokens;
+mod tools;
+mod transcript;
+mod watch;
+
 use anyhow::{Context, Result};
-use clap::Parser;
-use reqwest;
+use cache::Cache;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use failure::{Classify, Failure};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
+use std::fs::read_to_string;
 use std::io;
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::{fs::read_to_string, path::Path};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tiktoken_rs::CoreBPE;
+use tools::{
+    Tool, ToolDefinition, ToolOutput, ToolResult, discover_tools, execute_tool,
+    load_tool_definition, load_tool_manifest,
+};
 
-#[derive(serde::Serialize)]
+const OPENROUTER_API_URL: &str = "https://openrouter.ai/api/v1";
+
+/// Exit status when the answer was cut off because it reached the maximum number of tokens.
+const EXIT_TRUNCATED: i32 = 3;
+
+#[derive(serde::Serialize, Default)]
 struct OpenRouterRequest {
     model: String, // e.g., "mistralai/mistral-7b-instruct"
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<ToolDefinition>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>, // omitted, so that providers apply their own default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    logit_bias: BTreeMap<u32, f32>, // token id -> bias
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<Reasoning>, // ignored by models that don't reason
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>, // number of choices to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>, // end-user id, for the provider's abuse monitoring
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>, // tags of the request, for the provider's dashboards
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize)]
+struct Reasoning {
+    effort: ReasoningEffort,
+}
+
+/// How much a reasoning model should think before answering.
+#[derive(ValueEnum, serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(serde::Serialize)]
+struct JsonSchema {
+    name: String,
+    strict: bool,
+    schema: serde_json::Value,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(from = "WireMessage", into = "WireMessage")]
 struct Message {
-    role: String, // "user", "system", etc.
-    content: String,
+    role: String,            // "user", "system", "assistant" or "tool"
+    content: Option<String>, // may be null for assistant messages carrying tool calls
+    images: Vec<String>,     // image URLs (or data URLs) attached to the message
+    tool_calls: Option<Vec<ToolCall>>,
+    tool_call_id: Option<String>, // set on "tool" messages only
+    reasoning: Option<String>,    // the thinking of reasoning models, kept out of the answer
+}
+
+impl Message {
+    fn new(role: &str, content: String) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Some(content),
+            images: Vec::new(),
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+        }
+    }
+}
+
+// a message as it is sent to the API (and stored in sessions). Messages with images need the
+// array form of the content; all others keep it a plain string, which every provider understands.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct WireMessage {
+    role: String,
+    content: Option<Content>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    // the model doesn't need its earlier reasoning, so it is never sent back
+    #[serde(default, skip_serializing)]
+    reasoning: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl From<Message> for WireMessage {
+    fn from(message: Message) -> WireMessage {
+        let content = if message.images.is_empty() {
+            message.content.map(Content::Text)
+        } else {
+            let mut parts = Vec::new();
+            if let Some(text) = message.content {
+                parts.push(ContentPart::Text { text });
+            }
+            for url in message.images {
+                parts.push(ContentPart::ImageUrl {
+                    image_url: ImageUrl { url },
+                });
+            }
+            Some(Content::Parts(parts))
+        };
+
+        WireMessage {
+            role: message.role,
+            content,
+            tool_calls: message.tool_calls,
+            tool_call_id: message.tool_call_id,
+            reasoning: message.reasoning,
+        }
+    }
+}
+
+impl From<WireMessage> for Message {
+    fn from(message: WireMessage) -> Message {
+        let (content, images) = match message.content {
+            None => (None, Vec::new()),
+            Some(Content::Text(text)) => (Some(text), Vec::new()),
+            Some(Content::Parts(parts)) => {
+                let mut texts = Vec::new();
+                let mut images = Vec::new();
+                for part in parts {
+                    match part {
+                        ContentPart::Text { text } => texts.push(text),
+                        ContentPart::ImageUrl { image_url } => images.push(image_url.url),
+                    }
+                }
+                (Some(texts.join("\n")), images)
+            }
+        };
+
+        Message {
+            role: message.role,
+            content,
+            images,
+            tool_calls: message.tool_calls,
+            tool_call_id: message.tool_call_id,
+            reasoning: message.reasoning,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    call_type: String, // always "function", for now
+    function: FunctionCall,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct FunctionCall {
+    name: String,
+    arguments: String, // JSON-encoded, as generated by the model
+}
+
+impl FunctionCall {
+    /// Returns the arguments as a JSON object, for the APIs that take them decoded. They are only
+    /// validated when the tool runs, and invalid ones stay in the conversation (e.g. with
+    /// --tool-errors-to-model), so anything but an object is sent as `{"arguments": "<raw>"}`.
+    fn arguments_object(&self) -> serde_json::Value {
+        match serde_json::from_str(&self.arguments) {
+            Ok(object @ serde_json::Value::Object(_)) => object,
+            _ if self.arguments.trim().is_empty() => serde_json::json!({}),
+            _ => serde_json::json!({ "arguments": self.arguments }),
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct OpenRouterResponse {
+    #[serde(default)]
+    id: Option<String>, // the generation id, which OpenRouter support can look up
+    model: Option<String>, // the model that actually served the request
+    #[serde(default)]
     choices: Vec<Choice>,
+    usage: Option<Usage>, // not every provider reports it
+    #[serde(default)]
+    error: Option<ApiError>, // some providers report errors with a 200 status
+    #[serde(default)]
+    seed: Option<u64>, // echoed back by a few providers only
+    #[serde(default)]
+    system_fingerprint: Option<String>, // identifies the backend configuration
+}
+
+/// An error object embedded in an otherwise successful response.
+#[derive(serde::Deserialize)]
+struct ApiError {
+    message: String,
+    code: Option<serde_json::Value>,
+    metadata: Option<serde_json::Value>, // e.g. the reasons of a moderation flag
+}
+
+impl ApiError {
+    fn into_error(self) -> anyhow::Error {
+        let mut message = format!("API returned an error: {}", self.message);
+        if let Some(code) = self.code {
+            message.push_str(&format!(" (code {})", code));
+        }
+        if let Some(metadata) = self.metadata {
+            message.push_str(&format!("; metadata: {}", metadata));
+        }
+        anyhow::anyhow!(message)
+    }
 }
 
 #[derive(serde::Deserialize)]
 struct Choice {
     message: Message,
+    #[serde(default)]
+    finish_reason: Option<String>, // "stop", "length", "tool_calls", "content_filter", ...
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+}
+
+/// The final answer of a completion.
+struct Reply {
+    message: Message,
+    model: String,
+    usage: Option<Usage>, // summed over all requests of the completion
+    finish_reason: Option<String>,
+    id: Option<String>,         // of the last response
+    request_id: Option<String>, // the x-request-id header of the last response
+    alternatives: Vec<String>,  // the contents of the other choices, with -n
+}
+
+/// What --json-output prints.
+#[derive(serde::Serialize)]
+struct JsonOutput<'a> {
+    content: &'a str,
+    model: &'a str,
+    usage: Option<Usage>,
+    finish_reason: Option<&'a str>,
+    id: Option<&'a str>,
+    request_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    choices: Vec<&'a str>, // all of them, including the content, with -n
 }
 
 #[derive(Parser)]
 #[command(about = "rapidllm core command.", version)]
 struct Args {
-    /// AI model to use
-    #[arg(short = 'm', long = "model", default_value = "thudm/glm-4-32b:free")]
+    /// AI model to use. A model prefixed with "ollama/" selects the Ollama provider
+    #[arg(
+        short = 'm',
+        long = "model",
+        env = "RLM_MODEL",
+        default_value = "thudm/glm-4-32b:free"
+    )]
     model: String,
 
+    /// Model to fall back to when the model is unavailable (can be given multiple times, the
+    /// models are tried in order)
+    #[arg(long)]
+    fallback_model: Vec<String>,
+
+    /// Don't check the model names against the list of OpenRouter models
+    #[arg(long)]
+    no_validate_model: bool,
+
     #[arg(short = 'c', long = "character_limit", default_value = "16384")]
     character_limit: usize,
 
-    /// System prompt (optional)
+    /// Maximum number of tokens in all messages, counted with a tiktoken tokenizer (optional)
+    #[arg(long)]
+    token_limit: Option<usize>,
+
+    /// Maximum number of characters in the messages of the current turn (the user message, without
+    /// the system prompt and the history), checked on its own (optional)
+    #[arg(long)]
+    max_prompt_chars: Option<usize>,
+
+    /// User message (read from stdin if not given)
+    message: Option<String>,
+
+    /// User message, same as the positional argument
+    #[arg(short, long)]
+    prompt: Option<String>,
+
+    /// File to read the user message from
+    #[arg(long)]
+    prompt_file: Option<PathBuf>,
+
+    /// Image to attach to the user message, a file path or an http(s) URL (can be given
+    /// multiple times)
+    #[arg(long)]
+    image: Vec<String>,
+
+    /// File to append to the user message, in a code fence labeled with its path (can be given
+    /// multiple times)
+    #[arg(long)]
+    attach: Vec<PathBuf>,
+
+    /// Attach binary files base64-encoded, instead of refusing them
+    #[arg(long, requires = "attach")]
+    attach_base64: bool,
+
+    /// Beginning of the answer, which the model continues (e.g. "{" to get JSON)
+    #[arg(long)]
+    prefill: Option<String>,
+
+    /// Print only what the model generated after the --prefill, without the prefill itself
+    #[arg(long, requires = "prefill")]
+    assistant_prefix_strip: bool,
+
+    /// Remove the chain-of-thought blocks some models write into their answer (<think>...</think>,
+    /// or the ones given with --thinking-tag) from what is printed
+    #[arg(long)]
+    strip_thinking: bool,
+
+    /// Opening and closing tag of the blocks removed by --strip-thinking (can be given multiple
+    /// times)
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"], requires = "strip_thinking")]
+    thinking_tag: Vec<String>,
+
+    /// Shell command that gets the answer on stdin; what it prints is printed instead of the answer
+    #[arg(long, value_name = "COMMAND")]
+    postprocess: Option<String>,
+
+    /// Print the answer as it is when the --postprocess command fails, instead of failing
+    #[arg(long, requires = "postprocess")]
+    postprocess_fallback: bool,
+
+    /// Example exchanges for few-shot prompting: a JSON file with an array of user and assistant
+    /// messages, which are sent after the system message
+    #[arg(long)]
+    examples: Option<PathBuf>,
+
+    /// Read the conversation so far from stdin, as a JSON array of messages
+    #[arg(long, conflicts_with = "interactive")]
+    history_stdin: bool,
+
+    /// Read the conversation so far from a file, as an array of messages
+    #[arg(long, conflicts_with_all = ["interactive", "history_stdin"])]
+    history_file: Option<PathBuf>,
+
+    /// Format of the conversation given with --history-file or --history-stdin (by default taken
+    /// from the file extension, or JSON)
+    #[arg(long, value_enum)]
+    input_format: Option<InputFormat>,
+
+    /// Send the user message as it is, without stripping leading and trailing whitespace
+    #[arg(long)]
+    no_trim: bool,
+
+    /// Run again whenever this file (e.g. the --prompt-file, or a prompt or attachment) changes
+    /// (can be given multiple times)
+    #[arg(long, conflicts_with_all = ["interactive", "history_stdin"])]
+    watch: Vec<PathBuf>,
+
+    /// System prompt (optional, can be given multiple times to concatenate several prompts)
     #[arg(short, long)]
-    system: Option<String>,
+    system: Vec<String>,
+
+    /// Value of a variable in the system prompt files, which are templates with {{name}}
+    /// placeholders, as <name>=<value> (can be given multiple times)
+    #[arg(long, value_name = "NAME=VALUE")]
+    var: Vec<String>,
+
+    /// Name of a session, stored in ~/.config/rapidllm/sessions, to continue the conversation of
+    #[arg(long)]
+    session: Option<String>,
+
+    /// Add the new messages to the session file (the default)
+    #[arg(long, requires = "session")]
+    append_session: bool,
+
+    /// Replace the session file with only the latest exchange
+    #[arg(long, requires = "session", conflicts_with = "append_session")]
+    overwrite_session: bool,
+
+    /// Save the conversation under this name once the model has answered, to start other
+    /// conversations from it with --from-checkpoint
+    #[arg(long, value_name = "NAME")]
+    checkpoint: Option<String>,
+
+    /// Start from the conversation saved with --checkpoint under this name (instead of the one of
+    /// the --session)
+    #[arg(long, value_name = "NAME")]
+    from_checkpoint: Option<String>,
+
+    /// Delete the session with this name, and exit
+    #[arg(long, value_name = "NAME")]
+    clear_session: Option<String>,
+
+    /// Write the conversation to this file as a Markdown transcript
+    #[arg(long)]
+    transcript: Option<PathBuf>,
+
+    /// Tool directory, containing definition.json and exec (can be given multiple times)
+    #[arg(short = 't', long = "tool")]
+    tool_dir: Vec<PathBuf>,
+
+    /// Directory whose subdirectories are all loaded as tools (can be given multiple times)
+    #[arg(long)]
+    tools_dir: Vec<PathBuf>,
+
+    /// JSON file with an array of tool definitions, each with the path of its executable (can be
+    /// given multiple times)
+    #[arg(long)]
+    tool_manifest: Vec<PathBuf>,
+
+    /// Names of the tools that come first in the tool definitions sent to the model, in this
+    /// order; the other tools follow, sorted by name
+    #[arg(long, value_name = "NAME,...", value_delimiter = ',')]
+    tool_order: Vec<String>,
+
+    /// File with the absolute paths of the tool execs that may run, one per line; calls of any
+    /// other tool are refused
+    #[arg(long)]
+    allowed_tools: Option<PathBuf>,
+
+    /// Maximum number of model responses with tool calls in one run
+    #[arg(long, default_value = "10")]
+    max_iterations: usize,
+
+    /// Timeout of every API request in seconds
+    #[arg(long, default_value = "120")]
+    timeout: u64,
+
+    /// Proxy for all requests, e.g. http://proxy.example.com:8080 (overrides HTTP_PROXY and
+    /// HTTPS_PROXY)
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Number of times to retry requests that hit a rate limit (429) or a server error (5xx)
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    /// Delay before the first retry in milliseconds, doubled on every following one (and
+    /// randomized a little, so that many clients don't retry all at once)
+    #[arg(long, default_value = "1000")]
+    retry_delay: u64,
+
+    /// Wait for rate limits (429) to reset and then go on, instead of failing after --retries
+    #[arg(long)]
+    resume_on_rate_limit: bool,
+
+    /// Longest time in seconds that --resume-on-rate-limit waits in total for one request
+    #[arg(long, default_value = "900")]
+    max_rate_limit_wait: u64,
+
+    /// Give up after this many seconds in total, across all retries and tool calls
+    #[arg(long)]
+    deadline: Option<u64>,
+
+    /// Directory to cache responses in; an identical request is answered from the cache, without
+    /// sending it (streamed responses are not cached)
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Seconds after which cached responses expire (by default, they never do)
+    #[arg(long)]
+    cache_ttl: Option<u64>,
+
+    /// Neither use nor fill the cache, even with --cache-dir
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Kill tools that run for longer than this many seconds (by default, they may run forever)
+    #[arg(long)]
+    tool_timeout: Option<u64>,
+
+    /// Send tool failures (non-zero exit, invalid output) back to the model instead of aborting
+    #[arg(long)]
+    tool_errors_to_model: bool,
+
+    /// Tell the model that a tool it called isn't available, instead of aborting
+    #[arg(long)]
+    unknown_tools_to_model: bool,
+
+    /// Truncate tool results that would exceed the character limit, instead of aborting
+    #[arg(long)]
+    truncate_tool_output: bool,
+
+    /// Maximum size of what the model and the tools produce for one answer, in characters
+    /// (optional)
+    #[arg(long)]
+    output_limit: Option<usize>,
+
+    /// Maximum size of a single tool result, in characters (optional)
+    #[arg(long)]
+    tool_output_limit: Option<usize>,
 
     #[arg(long)]
     license: bool,
 
+    /// Print the names of the prompts in the directories of RLM_PROMPT_PATH and in
+    /// ~/.config/rapidllm/prompts (usable with --system), and exit
+    #[arg(long)]
+    list_prompts: bool,
+
+    /// Print the OpenRouter models with their context length and prices (prompt and completion,
+    /// per million tokens), and exit
+    #[arg(long)]
+    list_models: bool,
+
     #[arg(long)]
     raw_request: bool,
 
+    /// Compress the request body with gzip, for large contexts
+    #[arg(long)]
+    compress: bool,
+
+    /// Print the body of every request sent to the API to stderr, pretty-printed
+    #[arg(long)]
+    verbose_request_body: bool,
+
+    /// Print the request body that would be sent to stdout, and exit without sending it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the messages that are sent to stdout, each after a "--- <role> ---" line, followed by
+    /// "--- response ---" and the response
+    #[arg(long, conflicts_with_all = ["json_output", "interactive", "dry_run"])]
+    echo_input: bool,
+
+    /// Print the number of tokens of the messages (as counted for --token-limit), and exit
+    /// without sending them
+    #[arg(long)]
+    count_tokens: bool,
+
+    /// Log what is going on to stderr (same as --log-level debug)
     #[arg(long)]
     verbose: bool,
-}
 
-fn get_system_message(system_message: &str) -> Result<String> {
-    // Condition 1: Check custom prompt file in XDG config directory
-    if !system_message.contains('/') {
-        // if the string does not contain '/', then it cannot escape outside the promopts directory
-        // (e.g. you cannot read ../../etc/passwd through system_message). This should be enough
-        // since:
-        // 1. '/' is one of the only characters no filaname on Linux can use.
-        // 2. We are not targeting windows systems :) No need to worry about backslashes
+    /// Print nothing but the response and fatal errors (same as --log-level error, and no prompt
+    /// in interactive mode)
+    #[arg(short = 'q', long, conflicts_with_all = ["verbose", "log_level"])]
+    quiet: bool,
+
+    /// Level of the log messages: off, error, warn, info, debug or trace (default: warn)
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Write the log messages to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Maximum number of tokens the model may generate (optional)
+    #[arg(long)]
+    max_tokens: Option<u32>,
+
+    /// Number of completions to generate; all of them are printed, numbered (optional)
+    #[arg(short = 'n', long, value_parser = clap::value_parser!(u32).range(1..))]
+    num_completions: Option<u32>,
+
+    /// Reasoning effort, for models that support it (optional)
+    #[arg(long, value_enum)]
+    reasoning_effort: Option<ReasoningEffort>,
+
+    /// Sampling temperature, between 0 and 2 (optional)
+    #[arg(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass, between 0 and 1 (optional)
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Sample only from this many of the most likely tokens, 0 to disable (optional)
+    #[arg(long)]
+    top_k: Option<u32>,
+
+    /// Minimum probability of a token, relative to the most likely one, between 0 and 1 (optional)
+    #[arg(long)]
+    min_p: Option<f32>,
+
+    /// Penalty for tokens that already appeared at all, between -2 and 2 (optional)
+    #[arg(long, allow_hyphen_values = true)]
+    presence_penalty: Option<f32>,
+
+    /// Penalty for tokens by how often they already appeared, between -2 and 2 (optional)
+    #[arg(long, allow_hyphen_values = true)]
+    frequency_penalty: Option<f32>,
+
+    /// Sequence at which the model stops generating (can be given multiple times)
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Seed for sampling, for reproducible answers on providers that support it (optional)
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Bias of a token, as <token_id>:<bias> with a bias between -100 and 100 (can be given
+    /// multiple times)
+    #[arg(long, value_name = "TOKEN_ID:BIAS", allow_hyphen_values = true)]
+    logit_bias: Vec<String>,
+
+    /// Identifier of the end user the request is made for, to attribute usage to (optional)
+    #[arg(long, value_name = "ID")]
+    user: Option<String>,
+
+    /// Metadata to tag the request with, as <key>=<value>, e.g. to find it in the dashboard of the
+    /// provider (can be given multiple times)
+    #[arg(long, value_name = "KEY=VALUE")]
+    metadata: Vec<String>,
+
+    /// Keep chatting: after every reply, read the next user message from a line of stdin, until
+    /// Ctrl-D
+    #[arg(short = 'i', long)]
+    interactive: bool,
 
-        // If HOME isn't set, we should fail. We don't want situations where we interpret --system
-        // parameter in a way that user did not intend.
-        let home = env::var("HOME").context("HOME enviroment variable not set.")?;
+    /// API flavour of the server
+    #[arg(long, value_enum, default_value = "openrouter")]
+    provider: Provider,
 
-        let mut path_buf = PathBuf::from(home);
-        path_buf.push(".config");
-        path_buf.push("rapidllm");
-        path_buf.push("prompts");
-        path_buf.push(system_message);
-        path_buf.push("system.md");
+    /// Base URL of the API, e.g. http://localhost:8080/v1 for a local OpenAI-compatible server
+    /// (defaults to OpenRouter, or http://localhost:11434 with --provider ollama)
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Path of the chat endpoint under the base URL, for gateways with their own layout (defaults
+    /// to /chat/completions, or /api/chat with --provider ollama)
+    #[arg(long)]
+    chat_path: Option<String>,
+
+    /// URL of your app, sent as HTTP-Referer for OpenRouter's app rankings (optional)
+    #[arg(long)]
+    referer: Option<String>,
+
+    /// Name of your app, sent as X-Title for OpenRouter's app rankings (optional)
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Print an estimate of the cost of the request to stderr (always done in verbose mode)
+    #[arg(long)]
+    show_cost: bool,
+
+    /// Print the response as a JSON object with the content, the model and the token usage
+    #[arg(long)]
+    json_output: bool,
+
+    /// Print errors to stderr as a JSON object with the error, its kind and details (such as the
+    /// HTTP status and response body)
+    #[arg(long)]
+    json_errors: bool,
+
+    /// Make the model answer with a JSON object
+    #[arg(long)]
+    json: bool,
+
+    /// Make the model answer with JSON that matches the JSON schema in this file
+    #[arg(long, conflicts_with = "json")]
+    json_schema: Option<PathBuf>,
 
-        match std::fs::read_to_string(&path_buf) {
-            Ok(content) => return Ok(content),
+    /// When the answer is cut off at the maximum number of tokens, ask the model to continue it
+    #[arg(long = "continue")]
+    auto_continue: bool,
 
+    /// Maximum number of times an answer is continued with --continue
+    #[arg(long, default_value = "5")]
+    max_continuations: usize,
+
+    /// Print the response as it is generated
+    #[arg(long)]
+    stream: bool,
+
+    /// Render the markdown of the response, when stdout is a terminal
+    #[arg(long)]
+    render_markdown: bool,
+
+    /// Write the response to a file instead of stdout
+    #[arg(short, long, conflicts_with = "interactive")]
+    output: Option<PathBuf>,
+
+    /// Overwrite the file given with --output if it exists
+    #[arg(long, requires = "output")]
+    force: bool,
+}
+
+/// Returns the directories prompts are looked up in, in order: the ones in `RLM_PROMPT_PATH`
+/// (separated like in `PATH`), then the prompts directory of the config. Without a config
+/// directory, the ones in `RLM_PROMPT_PATH` are used alone; it is only an error if there are none.
+fn get_prompt_dirs() -> Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = match std::env::var_os("RLM_PROMPT_PATH") {
+        Some(path) => std::env::split_paths(&path)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    match get_config_dir() {
+        Ok(config_dir) => dirs.push(config_dir.join("prompts")),
+        Err(e) if dirs.is_empty() => return Err(e),
+        Err(_) => {}
+    }
+    Ok(dirs)
+}
+
+/// Returns the names of the prompts in the prompt directories, i.e. of their subdirectories that
+/// contain a system.md.
+fn list_prompts() -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for dir in get_prompt_dirs()? {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
             Err(e) => {
-                if e.kind() != ErrorKind::NotFound {
-                    // if the function failed for any other reason than ENOENT, we should inform
-                    // the user by erroring out
-                    return Err(e).context(format!(
-                        "Could not open file {}",
-                        path_buf.display().to_string()
-                    ));
+                return Err(e).context(format!("Could not read directory {}", dir.display()));
+            }
+        };
+
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("Could not read directory {}", dir.display()))?;
+            if entry.path().join("system.md").is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    // a prompt that is in several directories is still one prompt
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Resolves a --system value. Prompt files (but not literal prompts) are templates, whose
+/// placeholders are filled with `vars`.
+fn get_system_message(system_message: &str, vars: &HashMap<String, String>) -> Result<String> {
+    // "-" is the usual name of stdin; main makes sure nothing else wants to read it
+    if system_message == "-" {
+        return io::read_to_string(io::stdin()).context("Could not read from stdin");
+    }
+
+    // Condition 1: Check custom prompt file in the prompt directories
+    if is_plain_name(system_message) {
+        // a plain name cannot escape outside the promopts directory (e.g. you cannot read
+        // ../../etc/passwd or even ../system.md through system_message). See is_plain_name for
+        // why that is enough.
+
+        // If there is no prompt directory at all (HOME isn't set, and neither is RLM_PROMPT_PATH),
+        // we should fail. We don't want situations where we interpret --system parameter in a way
+        // that user did not intend.
+        for dir in get_prompt_dirs()? {
+            let path_buf = dir.join(system_message).join("system.md");
+
+            match std::fs::read_to_string(&path_buf) {
+                Ok(content) => {
+                    return template::fill(&content, vars)
+                        .with_context(|| format!("Could not fill in {}", path_buf.display()));
+                }
+
+                Err(e) => {
+                    if e.kind() != ErrorKind::NotFound {
+                        // if the function failed for any other reason than ENOENT, we should
+                        // inform the user by erroring out
+                        return Err(e)
+                            .context(format!("Could not open file {}", path_buf.display()));
+                    }
                 }
             }
         }
@@ -114,13 +868,16 @@ fn get_system_message(system_message: &str) -> Result<String> {
         // system message itself
     }
 
-    // if the system_message does have '/', then it is either:
+    // if the system_message does have a path separator, then it is either:
     // 1. A filepath.
     // 2. A system message that itself contains '/'.
 
     // Condition 2: Check if input is a valid file path
     match fs::read_to_string(system_message) {
-        Ok(content) => return Ok(content),
+        Ok(content) => {
+            return template::fill(&content, vars)
+                .with_context(|| format!("Could not fill in {}", system_message));
+        }
 
         Err(e) => {
             if e.kind() != ErrorKind::NotFound {
@@ -133,139 +890,3012 @@ fn get_system_message(system_message: &str) -> Result<String> {
     Ok(system_message.to_string())
 }
 
-fn get_api_key() -> Result<std::string::String> {
+/// Parses the --var options into the values of the template variables.
+fn get_template_vars(args: &Args) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for var in &args.var {
+        let (name, value) = var
+            .split_once('=')
+            .with_context(|| format!("--var must be <name>=<value>, but {} was given", var))
+            .classify(Failure::Config)?;
+        vars.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Returns true if the name can be joined to a directory path without escaping that directory.
+///
+/// That is the case when the name is a single normal path component: not "." or "..", and without
+/// a root or a Windows prefix (as in "C:"). Separators are rejected outright; backslashes
+/// everywhere, since a name that only works on some systems is a bad name anyway.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !name.contains(['/', '\\'])
+}
+
+/// Returns the rapidllm configuration directory: `~/.config/rapidllm`, or `%APPDATA%\rapidllm` on
+/// Windows.
+fn get_config_dir() -> Result<PathBuf> {
+    if cfg!(windows) {
+        let appdata = env::var("APPDATA").context("APPDATA enviroment variable not set.")?;
+        return Ok(PathBuf::from(appdata).join("rapidllm"));
+    }
+
     let home = env::var("HOME").context("HOME enviroment variable not set.")?;
 
-    let path = Path::new(&home)
-        .join(".config")
-        .join("rapidllm")
-        .join("openrouter")
+    let mut path_buf = PathBuf::from(home);
+    path_buf.push(".config");
+    path_buf.push("rapidllm");
+    Ok(path_buf)
+}
+
+/// Returns the API key of a provider. Ollama doesn't use keys at all, and when the key is
+/// `optional`, a missing one is fine as well; either way, that just means no Authorization header.
+fn get_provider_api_key(provider: Provider, optional: bool) -> Result<Option<String>> {
+    let Some((service, variable, dir)) = provider.api_key_source() else {
+        return Ok(None);
+    };
+    match get_api_key(variable, dir) {
+        Ok(api_key) => {
+            debug!("Read {} API key.", service);
+            Ok(Some(api_key))
+        }
+        Err(_) if optional => Ok(None),
+        Err(e) => Err(e)
+            .with_context(|| format!("Could not retrieve {} API key", service))
+            .classify(Failure::Config),
+    }
+}
+
+fn get_api_key(variable: &str, dir: &str) -> Result<std::string::String> {
+    // the environment variable takes precedence, since it is the easiest thing to set in CI or
+    // containers. An empty variable is treated as unset.
+    if let Ok(api_key) = env::var(variable) {
+        let api_key = api_key.trim();
+        if !api_key.is_empty() {
+            return Ok(api_key.to_string());
+        }
+    }
+
+    let path = get_config_dir()
+        .with_context(|| format!("{} environment variable not set", variable))?
+        .join(dir)
         .join("api_key");
 
     // more verbose messages (e.g. "No such file or directory.")
-    read_to_string(path).context("Could not read ~/.config/rapidllm/openrouter/api_key")
+    let api_key = read_to_string(&path).with_context(|| {
+        format!(
+            "{} environment variable not set, and could not read {}",
+            variable,
+            path.display()
+        )
+    })?;
+
+    // a trailing newline (e.g. from `echo KEY > api_key`) is not a part of the key
+    Ok(api_key.trim().to_string())
 }
 
-fn get_user_message() -> Result<String> {
-    let stdin = io::stdin();
-    // retrieve user message, explicit failure if input is non-UTF8
-    let input = match io::read_to_string(stdin) {
-        Ok(read) => read,
-        Err(e) => return Err(e).context("Could not read from stdin"),
+/// Returns the user message, from the first of these that is given:
+/// 1. the positional argument, or --prompt (only one of them may be given);
+/// 2. --prompt-file;
+/// 3. stdin.
+fn get_user_message(args: &Args) -> Result<String> {
+    let sources = [
+        args.message.is_some(),
+        args.prompt.is_some(),
+        args.prompt_file.is_some(),
+    ];
+    if sources.iter().filter(|&&given| given).count() > 1 {
+        // silently preferring one of them would most likely drop something the user meant to send
+        return Err(anyhow::anyhow!(
+            "More than one user message given: use either the positional argument, --prompt or --prompt-file (stdin is only read when none of them is given)"
+        ))
+        .classify(Failure::Config);
+    }
+
+    let input = if let Some(message) = args.message.as_ref().or(args.prompt.as_ref()) {
+        message.clone()
+    } else if let Some(path) = &args.prompt_file {
+        let file =
+            fs::File::open(path).with_context(|| format!("Could not read {}", path.display()))?;
+        read_limited(file, args).with_context(|| format!("Could not read {}", path.display()))?
+    } else {
+        // retrieve user message, explicit failure if input is non-UTF8
+        read_limited(io::stdin(), args).context("Could not read from stdin")?
     };
 
-    Ok(input.trim().to_string())
+    if args.no_trim {
+        Ok(input)
+    } else {
+        Ok(input.trim().to_string())
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// Reads a user message, but gives up as soon as it is longer than the character limit on its own,
+/// instead of buffering a huge input (say, a file piped in by accident) that would be rejected
+/// anyway. Unless --no-trim is given, surrounding whitespace doesn't count, since it is stripped.
+fn read_limited(mut reader: impl io::Read, args: &Args) -> Result<String> {
+    let mut input = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    // only the new bytes are counted; leading whitespace is skipped until the text starts, and the
+    // whitespace at the end so far is subtracted, since more text may still follow it
+    let mut started = args.no_trim;
+    let mut size = 0;
+    let mut trailing = 0;
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        input.extend_from_slice(&chunk[..read]);
+
+        for &byte in &chunk[..read] {
+            let whitespace = !args.no_trim && byte.is_ascii_whitespace();
+            if !started && whitespace {
+                continue;
+            }
+            started = true;
+            size += 1;
+            trailing = if whitespace { trailing + 1 } else { 0 };
+        }
+        if size - trailing > args.character_limit {
+            return Err(anyhow::anyhow!(
+                "Input too long: at least {} bytes given, but the character limit is {}",
+                size - trailing,
+                args.character_limit
+            ))
+            .classify(Failure::Limit);
+        }
+    }
+    String::from_utf8(input).context("Input is not valid UTF-8")
+}
+
+/// Appends the --attach files to a user message, taking them, since only the first message gets
+/// them.
+fn attach(message: String, attachments: &mut Vec<String>) -> String {
+    if attachments.is_empty() {
+        return message;
+    }
+    let mut parts = vec![message];
+    parts.append(attachments);
+    parts.join("\n\n")
+}
+
+/// Prints the messages for --echo-input, in a format that is easy to split again: every message
+/// starts with a "--- <role> ---" line. The response follows its own "--- response ---" line, if it
+/// is printed as well.
+fn echo_input(messages: &[Message], response_follows: bool) {
+    for message in messages {
+        println!("--- {} ---", message.role);
+        println!("{}", message.content.as_deref().unwrap_or_default());
+    }
+    if response_follows {
+        println!("--- response ---");
+    }
+}
+
+/// Parses a conversation. TOML has no top-level arrays, so there the messages are a `[[messages]]`
+/// array of tables.
+fn parse_history(input: &str, format: InputFormat, source: &str) -> Result<Vec<Message>> {
+    #[derive(serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct TomlHistory {
+        messages: Vec<Message>,
+    }
+
+    match format {
+        InputFormat::Json => serde_json::from_str(input).with_context(|| {
+            format!(
+                "Could not parse the history {} (expected a JSON array of messages)",
+                source
+            )
+        }),
+        InputFormat::Yaml => serde_yaml::from_str(input).with_context(|| {
+            format!(
+                "Could not parse the history {} (expected a YAML list of messages)",
+                source
+            )
+        }),
+        InputFormat::Toml => toml::from_str(input)
+            .map(|history: TomlHistory| history.messages)
+            .with_context(|| {
+                format!(
+                    "Could not parse the history {} (expected a [[messages]] array of tables)",
+                    source
+                )
+            }),
+    }
+}
+
+/// Reads the conversation history given with --history-stdin, by default a JSON array of messages.
+fn get_history_from_stdin(args: &Args) -> Result<Vec<Message>> {
+    let input = io::read_to_string(io::stdin()).context("Could not read from stdin")?;
+    parse_history(
+        &input,
+        args.input_format.unwrap_or(InputFormat::Json),
+        "on stdin",
+    )
+}
+
+/// Reads the conversation history given with --history-file.
+fn get_history_from_file(path: &Path, args: &Args) -> Result<Vec<Message>> {
+    let input =
+        read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let format = args
+        .input_format
+        .or_else(|| InputFormat::from_path(path))
+        .unwrap_or(InputFormat::Json);
+    parse_history(&input, format, &format!("in {}", path.display()))
+}
+
+/// Reads the example exchanges given with --examples. Only user and assistant messages make sense
+/// as examples, anything else is an error.
+fn get_examples(path: &Path) -> Result<Vec<Message>> {
+    let content =
+        read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let examples: Vec<Message> = serde_json::from_str(&content).with_context(|| {
+        format!(
+            "Could not parse {} (expected a JSON array of messages)",
+            path.display()
+        )
+    })?;
+
+    for (index, example) in examples.iter().enumerate() {
+        if example.role != "user" && example.role != "assistant" {
+            return Err(anyhow::anyhow!(
+                "Example {} in {} has the role {}, but only user and assistant are allowed",
+                index + 1,
+                path.display(),
+                example.role
+            ));
+        }
+    }
+    Ok(examples)
+}
+
+/// Returns the response format requested with --json or --json-schema, if any.
+fn get_response_format(args: &Args) -> Result<Option<ResponseFormat>> {
+    if let Some(path) = &args.json_schema {
+        let content =
+            read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let schema = serde_json::from_str(&content)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        return Ok(Some(ResponseFormat::JsonSchema {
+            json_schema: JsonSchema {
+                name: "response".to_string(),
+                strict: true,
+                schema,
+            },
+        }));
+    }
+
+    Ok(args.json.then_some(ResponseFormat::JsonObject))
+}
+
+/// Parses the --metadata options into the map sent to the API.
+fn get_metadata(args: &Args) -> Result<BTreeMap<String, String>> {
+    let mut metadata = BTreeMap::new();
+    for entry in &args.metadata {
+        let (key, value) = entry
+            .split_once('=')
+            .with_context(|| format!("--metadata must be <key>=<value>, but {} was given", entry))
+            .classify(Failure::Config)?;
+        metadata.insert(key.trim().to_string(), value.to_string());
+    }
+    Ok(metadata)
+}
+
+/// Parses the --logit-bias options into the map sent to the API.
+fn get_logit_bias(args: &Args) -> Result<BTreeMap<u32, f32>> {
+    let mut logit_bias = BTreeMap::new();
+    for entry in &args.logit_bias {
+        let (token, bias) = entry
+            .split_once(':')
+            .with_context(|| {
+                format!(
+                    "--logit-bias must be <token_id>:<bias>, but {} was given",
+                    entry
+                )
+            })
+            .classify(Failure::Config)?;
+        let token: u32 = token
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid token id in --logit-bias {}", entry))
+            .classify(Failure::Config)?;
+        let bias: f32 = bias
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid bias in --logit-bias {}", entry))
+            .classify(Failure::Config)?;
+        check_range("The bias of --logit-bias", Some(bias), -100.0..=100.0)?;
+        logit_bias.insert(token, bias);
+    }
+    Ok(logit_bias)
+}
+
+/// Whether the requests go to OpenAI models, which don't support the sampling parameters of open
+/// models (top_k and min_p), directly or through OpenRouter.
+fn ignores_open_sampling(args: &Args) -> bool {
+    let openai_url = args
+        .base_url
+        .as_deref()
+        .and_then(|url| reqwest::Url::parse(url).ok())
+        .is_some_and(|url| url.host_str() == Some("api.openai.com"));
+    openai_url
+        || std::iter::once(&args.model)
+            .chain(&args.fallback_model)
+            .any(|model| model.starts_with("openai/"))
+}
+
+/// Errors out if an optional sampling parameter is given, but is out of its accepted range.
+fn check_range(name: &str, value: Option<f32>, range: RangeInclusive<f32>) -> Result<()> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(anyhow::anyhow!(
+            "{} must be between {} and {}, but {} was given",
+            name,
+            range.start(),
+            range.end(),
+            value
+        ))
+        .classify(Failure::Config),
+        _ => Ok(()),
+    }
+}
+
+fn check_token_limit(
+    tokenizer: Option<&CoreBPE>,
+    token_limit: Option<usize>,
+    messages: &[Message],
+) -> Result<()> {
+    let (Some(bpe), Some(token_limit)) = (tokenizer, token_limit) else {
+        return Ok(());
+    };
+
+    let token_count = tokens::count_tokens(bpe, messages);
+    if token_count > token_limit {
+        return Err(anyhow::anyhow!(
+            "Input too long: {} tokens given, but the token limit is {}",
+            token_count,
+            token_limit
+        ))
+        .classify(Failure::Limit);
+    }
+    Ok(())
+}
+
+/// The HTTP client and the credentials, shared by all requests of a run.
+struct Backend {
+    client: reqwest::Client,
+    api_key: Option<String>, // local servers don't need any
+    provider: Provider,
+    base_url: String,
+    chat_path: String,
+    cache: Option<Cache>,
+    deadline: Option<Deadline>,
+}
+
+impl Backend {
+    fn chat_url(&self) -> String {
+        format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            self.chat_path.trim_start_matches('/')
+        )
+    }
+
+    /// Returns the cache key of a request, if there is a cache and the request can be cached.
+    fn cache_key(&self, request_body: &OpenRouterRequest) -> Result<Option<String>> {
+        if self.cache.is_none() || request_body.stream {
+            return Ok(None);
+        }
+        let body = serde_json::to_vec(&self.provider.request_body(request_body))
+            .context("Could not serialize request")?;
+        Ok(Some(Cache::key(&self.chat_url(), &body)))
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum RequestBody<'a> {
+    OpenRouter(&'a OpenRouterRequest),
+    Ollama(ollama::OllamaRequest<'a>),
+    Anthropic(anthropic::AnthropicRequest<'a>),
+}
+
+impl Backend {
+    /// Whether requests go to OpenRouter itself, whose list of models (and their prices) is known.
+    fn is_openrouter(&self) -> bool {
+        self.provider == Provider::Openrouter && self.base_url == OPENROUTER_API_URL
+    }
+}
+
+/// The end of the time the run may take, given with --deadline.
+#[derive(Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    seconds: u64,
+}
+
+impl Deadline {
+    fn new(seconds: u64) -> Deadline {
+        Deadline {
+            at: Instant::now() + Duration::from_secs(seconds),
+            seconds,
+        }
+    }
+
+    fn exceeded<T>(&self) -> Result<T> {
+        Err(anyhow::anyhow!(
+            "Deadline exceeded: the run took longer than {} seconds",
+            self.seconds
+        ))
+        .classify(Failure::Limit)
+    }
+
+    /// Returns the time that is left, or an error when there is none.
+    fn remaining(&self) -> Result<Duration> {
+        match self.at.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => self.exceeded(),
+        }
+    }
+}
+
+/// Awaits the future, but gives up once the deadline (if any) has passed.
+async fn with_deadline<T>(
+    deadline: Option<&Deadline>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(deadline) = deadline else {
+        return future.await;
+    };
+    match tokio::time::timeout(deadline.remaining()?, future).await {
+        Ok(result) => result,
+        Err(_) => deadline.exceeded(),
+    }
+}
+
+/// The format of a conversation read with --history-file or --history-stdin.
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum InputFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl InputFormat {
+    /// Guesses the format from the extension of the file, if it has a known one.
+    fn from_path(path: &Path) -> Option<InputFormat> {
+        match path.extension()?.to_str()? {
+            "json" => Some(InputFormat::Json),
+            "yaml" | "yml" => Some(InputFormat::Yaml),
+            "toml" => Some(InputFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// The API flavour spoken by the server.
+#[derive(ValueEnum, serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Provider {
+    /// OpenRouter, or any other OpenAI-compatible API
+    Openrouter,
+    /// Ollama's native chat API
+    Ollama,
+    /// Anthropic's native messages API
+    Anthropic,
+}
+
+impl Provider {
+    fn name(self) -> &'static str {
+        match self {
+            Provider::Openrouter => "openrouter",
+            Provider::Ollama => "ollama",
+            Provider::Anthropic => "anthropic",
+        }
+    }
+
+    fn default_base_url(self) -> &'static str {
+        match self {
+            Provider::Openrouter => OPENROUTER_API_URL,
+            Provider::Ollama => ollama::DEFAULT_BASE_URL,
+            Provider::Anthropic => anthropic::DEFAULT_BASE_URL,
+        }
+    }
+
+    /// Whether the model continues a partial assistant message at the end of the request.
+    fn supports_prefill(self) -> bool {
+        match self {
+            Provider::Openrouter | Provider::Anthropic => true,
+            Provider::Ollama => false,
+        }
+    }
+
+    /// Whether responses can be streamed. Only the Server-Sent Events of the OpenAI API are read;
+    /// Ollama streams newline-delimited JSON, and Anthropic sends events of its own.
+    fn supports_streaming(self) -> bool {
+        self == Provider::Openrouter
+    }
+
+    /// Whether the request fields only the OpenAI API has (n, logit_bias and metadata) are sent.
+    fn supports_openai_fields(self) -> bool {
+        self == Provider::Openrouter
+    }
+
+    /// The name of the service, the environment variable and the directory under the config
+    /// directory that hold the API key, for the providers that need one.
+    fn api_key_source(self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            Provider::Openrouter => Some(("OpenRouter", "OPENROUTER_API_KEY", "openrouter")),
+            Provider::Ollama => None,
+            Provider::Anthropic => Some(("Anthropic", "ANTHROPIC_API_KEY", "anthropic")),
+        }
+    }
+
+    fn chat_path(self) -> &'static str {
+        match self {
+            Provider::Openrouter => "/chat/completions",
+            Provider::Ollama => "/api/chat",
+            Provider::Anthropic => "/messages",
+        }
+    }
+
+    /// Returns the request body in the shape this provider expects.
+    fn request_body(self, request_body: &OpenRouterRequest) -> RequestBody<'_> {
+        match self {
+            Provider::Openrouter => RequestBody::OpenRouter(request_body),
+            Provider::Ollama => RequestBody::Ollama(ollama::to_request(request_body)),
+            Provider::Anthropic => RequestBody::Anthropic(anthropic::to_request(request_body)),
+        }
+    }
+
+    fn parse_response(self, response_text: &str) -> Result<OpenRouterResponse> {
+        match self {
+            Provider::Openrouter => serde_json::from_str::<OpenRouterResponse>(response_text)
+                .with_context(|| {
+                    format!(
+                        "Failed to parse JSON of the API request response; Response body was: {}",
+                        response_text
+                    )
+                }),
+            Provider::Ollama => ollama::parse_response(response_text),
+            Provider::Anthropic => anthropic::parse_response(response_text),
+        }
+    }
+}
+
+/// Returns the size of all messages in bytes, as counted against the character limit.
+fn get_total_size(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|message| message.content.as_deref().map_or(0, str::len))
+        .sum()
+}
+
+/// Checks the messages against the character limit (and the token limit, if there is one), and
+/// returns their total size in bytes.
+fn check_limits(args: &Args, tokenizer: Option<&CoreBPE>, messages: &[Message]) -> Result<usize> {
+    let total_size = get_total_size(messages);
+    if total_size > args.character_limit {
+        return Err(anyhow::anyhow!(
+            "Input too long: {} bytes given, but the character limit is {}",
+            total_size,
+            args.character_limit
+        ))
+        .classify(Failure::Limit);
+    }
+
+    check_token_limit(tokenizer, args.token_limit, messages)?;
+    Ok(total_size)
+}
+
+/// Checks the messages of the current turn against the --max-prompt-chars, if there is one. Unlike
+/// the character limit, which is about the whole conversation, this is about what was just given.
+fn check_prompt_size(args: &Args, prompt: &[Message]) -> Result<()> {
+    let prompt_size = get_total_size(prompt);
+    match args.max_prompt_chars {
+        Some(limit) if prompt_size > limit => Err(anyhow::anyhow!(
+            "Prompt too long: {} characters given in this turn, but --max-prompt-chars is {}",
+            prompt_size,
+            limit
+        ))
+        .classify(Failure::Limit),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the size of the assistant and tool messages in characters, as counted against the
+/// output limit.
+fn get_output_size(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .filter(|message| message.role == "assistant" || message.role == "tool")
+        .map(|message| message.content.as_deref().map_or(0, str::len))
+        .sum()
+}
+
+/// Checks the output of a completion so far against the --output-limit, if there is one.
+fn check_output_limit(args: &Args, output_size: usize) -> Result<()> {
+    match args.output_limit {
+        Some(output_limit) if output_size > output_limit => Err(anyhow::anyhow!(
+            "Output too long: {} characters generated, but the output limit is {}",
+            output_size,
+            output_limit
+        ))
+        .classify(Failure::Limit),
+        _ => Ok(()),
+    }
+}
+
+const CONTINUE_PROMPT: &str =
+    "Your answer was cut off. Continue exactly where you left off, without repeating anything.";
+
+const TRUNCATED_MARKER: &str = "\n[truncated]";
+
+/// Cuts a tool result down to `budget` characters, including the marker that tells the model it
+/// was cut. Returns `None` if the budget is too small to hold even the marker, since a result cut
+/// down to nothing without it would look like the whole output of the tool.
+fn truncate_output(output: String, budget: usize) -> Option<String> {
+    if output.len() <= budget {
+        return Some(output);
+    }
+
+    let mut end = budget.checked_sub(TRUNCATED_MARKER.len())?;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(format!("{}{}", &output[..end], TRUNCATED_MARKER))
+}
+
+/// Sends the request, executing the tool calls of the model and sending their results back, until
+/// the model answers without calling any tools. The tool calls and results are appended to the
+/// messages of the request, and the final assistant message is returned.
+async fn complete(
+    backend: &Backend,
+    args: &Args,
+    tools: &HashMap<String, Tool>,
+    tokenizer: Option<&CoreBPE>,
+    request_body: &mut OpenRouterRequest,
+) -> Result<Reply> {
+    // number of responses that contained tool calls so far
+    let mut iterations = 0;
+    let mut usage: Option<Usage> = None;
+
+    // with --continue, the parts of an answer that was cut off so far, and where the messages
+    // asking for its continuation start
+    let mut continuations = 0;
+    let mut continued = String::new();
+    let mut continue_start = None;
+    // the output of this completion (what the model and the tools produce) starts here
+    let output_start = request_body.messages.len();
+    let print_stream = request_body.stream
+        && !args.json_output
+        && args.output.is_none()
+        && !renders_markdown(args)
+        && !transforms_answer(args);
+
+    // the prefill is only sent with the first request; the model continues the partial assistant
+    // message, so it is part of the answer
+    let mut prefill = args
+        .prefill
+        .clone()
+        .filter(|_| backend.provider.supports_prefill());
+
+    // every iteration is one API request; we loop for as long as the model keeps calling tools
+    loop {
+        let prefilled = prefill.take();
+        if let Some(prefill) = &prefilled {
+            request_body
+                .messages
+                .push(Message::new("assistant", prefill.clone()));
+        }
+        let cache_key = backend.cache_key(request_body)?;
+        let cached = cache_key
+            .as_deref()
+            .zip(backend.cache.as_ref())
+            .and_then(|(key, cache)| cache.get(key));
+
+        let mut raw_response = None;
+        let (response_json, request_id) = if let Some(response_text) = cached {
+            if prefilled.is_some() {
+                request_body.messages.pop();
+            }
+            debug!("Answering from the cache");
+            let response_json = backend.provider.parse_response(&response_text)?;
+            raw_response = Some(response_text);
+            (response_json, None)
+        } else {
+            // the whole request is bounded by the deadline, retries and reading the answer included
+            let response = with_deadline(
+                backend.deadline.as_ref(),
+                send_with_fallback(backend, request_body, args),
+            )
+            .await;
+            if prefilled.is_some() {
+                request_body.messages.pop();
+            }
+            let mut response = response?;
+            let mut request_id = get_request_id(&response);
+            log_rate_limits(&response);
+
+            // when streaming, the content is printed as it arrives (unless it goes into a JSON
+            // object)
+            let response_json = if request_body.stream {
+                if let Some(prefill) = &prefilled
+                    && print_stream
+                    && !args.assistant_prefix_strip
+                {
+                    print!("{}", prefill);
+                }
+                with_deadline(
+                    backend.deadline.as_ref(),
+                    stream::read_response(response, print_stream),
+                )
+                .await?
+            } else {
+                // a body that was cut off on the way (rather than one that is no valid answer) is
+                // requested once more
+                let mut resent = false;
+                let (response_json, response_text) = loop {
+                    let response_text = with_deadline(backend.deadline.as_ref(), async {
+                        response.text().await.map_err(|e| {
+                            network_error(e, "Failed to read API response", args.timeout)
+                        })
+                    })
+                    .await?;
+                    match backend.provider.parse_response(&response_text) {
+                        Ok(response_json) => break (response_json, response_text),
+                        Err(_) if is_truncated(&response_text) && !resent => {
+                            warn!(
+                                "The API response was cut off after {} bytes, sending the request again.",
+                                response_text.len()
+                            );
+                        }
+                        Err(e) if is_truncated(&response_text) => {
+                            return Err(e)
+                                .context(format!(
+                                    "The API response was cut off after {} bytes, also when it was requested again",
+                                    response_text.len()
+                                ))
+                                .classify(Failure::Network);
+                        }
+                        Err(e) => return Err(e),
+                    }
+
+                    resent = true;
+                    if let Some(prefill) = &prefilled {
+                        request_body
+                            .messages
+                            .push(Message::new("assistant", prefill.clone()));
+                    }
+                    let resent_response = with_deadline(
+                        backend.deadline.as_ref(),
+                        send_with_fallback(backend, request_body, args),
+                    )
+                    .await;
+                    if prefilled.is_some() {
+                        request_body.messages.pop();
+                    }
+                    response = resent_response?;
+                    request_id = get_request_id(&response);
+                    log_rate_limits(&response);
+                };
+                // only answers are cached, so that a failure isn't repeated
+                if response_json.error.is_none()
+                    && !response_json.choices.is_empty()
+                    && let (Some(cache), Some(key)) = (&backend.cache, &cache_key)
+                    && let Err(e) = cache.put(key, &response_text)
+                {
+                    warn!("Could not cache the response: {:#}", e);
+                }
+                raw_response = Some(response_text);
+                response_json
+            };
+            (response_json, request_id)
+        };
+        // logged before any error, since they identify failed calls as well
+        if let Some(id) = &response_json.id {
+            debug!("Generation id: {}", id);
+        }
+        if let Some(request_id) = &request_id {
+            debug!("Request id: {}", request_id);
+        }
+        if let Some(error) = response_json.error {
+            return Err(error.into_error());
+        }
+
+        // a seed only reproduces an answer on the same backend configuration
+        if request_body.seed.is_some() {
+            match response_json.seed {
+                Some(seed) => debug!("The API echoed the seed back: {}", seed),
+                None => debug!("The API did not echo the seed back"),
+            }
+            if let Some(fingerprint) = &response_json.system_fingerprint {
+                debug!("System fingerprint: {}", fingerprint);
+            }
+        }
+
+        if let Some(response_usage) = response_json.usage {
+            let total = usage.get_or_insert_with(Usage::default);
+            total.prompt_tokens += response_usage.prompt_tokens;
+            total.completion_tokens += response_usage.completion_tokens;
+            total.total_tokens += response_usage.total_tokens;
+        }
+        let model = response_json
+            .model
+            .unwrap_or_else(|| request_body.model.clone());
+        info!("Response from model {}", model);
+
+        let mut choices = response_json.choices.into_iter();
+        let first_choice = choices.next().context("No response from LLM API")?;
+        // with -n, the other choices are only printed; the conversation goes on with the first
+        let mut alternatives: Vec<String> = choices
+            .map(|choice| choice.message.content.unwrap_or_default())
+            .collect();
+
+        let mut message = first_choice.message;
+        let finish_reason = first_choice.finish_reason;
+        if let Some(reasoning) = message.reasoning.as_deref() {
+            debug!("Reasoning of the model:\n{}", reasoning);
+        }
+        check_output_limit(
+            args,
+            get_output_size(&request_body.messages[output_start..])
+                + message.content.as_deref().map_or(0, str::len),
+        )?;
+
+        let tool_calls = match &message.tool_calls {
+            Some(tool_calls) if !tool_calls.is_empty() => tool_calls.clone(),
+            _ => {
+                // some providers echo the prefill in the answer, others only send what follows it
+                if let Some(prefill) = prefilled {
+                    for alternative in &mut alternatives {
+                        if !alternative.starts_with(&prefill) {
+                            alternative.insert_str(0, &prefill);
+                        }
+                    }
+                    let content = message.content.unwrap_or_default();
+                    message.content = if content.starts_with(&prefill) {
+                        debug!("The answer already starts with the prefill");
+                        Some(content)
+                    } else {
+                        Some(prefill + &content)
+                    };
+                }
+
+                // no content is fine next to tool calls, but without them there is nothing at all
+                if message.content.is_none() {
+                    let mut error = String::from(
+                        "Malformed response: the model returned neither content nor tool calls",
+                    );
+                    // --verbose is the same as --log-level debug
+                    if log::log_enabled!(log::Level::Debug)
+                        && let Some(raw_response) = raw_response
+                    {
+                        error.push_str(&format!("; Response body was: {}", raw_response));
+                    }
+                    return Err(anyhow::anyhow!(error)).classify(Failure::Server);
+                }
+
+                if args.auto_continue
+                    && finish_reason.as_deref() == Some("length")
+                    && continuations < args.max_continuations
+                {
+                    continuations += 1;
+                    info!(
+                        "The answer was cut off, asking the model to continue ({}/{})",
+                        continuations, args.max_continuations
+                    );
+                    let partial = message.content.unwrap_or_default();
+                    continued.push_str(&partial);
+                    continue_start.get_or_insert(request_body.messages.len());
+                    request_body
+                        .messages
+                        .push(Message::new("assistant", partial));
+                    request_body
+                        .messages
+                        .push(Message::new("user", CONTINUE_PROMPT.to_string()));
+                    continue;
+                }
+
+                // the parts become one message again, as if the answer had never been cut off
+                if let Some(start) = continue_start {
+                    request_body.messages.truncate(start);
+                    continued.push_str(message.content.as_deref().unwrap_or_default());
+                    message.content = Some(continued);
+                }
+                return Ok(Reply {
+                    message,
+                    model,
+                    usage,
+                    finish_reason,
+                    id: response_json.id,
+                    request_id,
+                    alternatives,
+                });
+            }
+        };
+
+        if !alternatives.is_empty() {
+            debug!(
+                "Only the first of {} choices is used for the tool calls",
+                alternatives.len() + 1
+            );
+        }
+
+        // a model that never stops calling tools would otherwise spend API credits forever
+        iterations += 1;
+        if iterations > args.max_iterations {
+            return Err(anyhow::anyhow!(
+                "Exceeded max tool-call iterations ({})",
+                args.max_iterations
+            ))
+            .classify(Failure::Limit);
+        }
+
+        // the assistant message has to be in the context, so that the tool results refer to
+        // existing tool calls
+        request_body.messages.push(message);
+
+        // the tools of one response don't depend on each other, so they all run at once; the
+        // results are then handled in the order of the calls
+        let mut handles = Vec::new();
+        for tool_call in &tool_calls {
+            // no tool is started once the deadline has passed, and none may run past it
+            let mut tool_timeout = args.tool_timeout.map(Duration::from_secs);
+            if let Some(deadline) = &backend.deadline {
+                let remaining = deadline.remaining()?;
+                tool_timeout =
+                    Some(tool_timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+            }
+            let Some(tool) = tools.get(&tool_call.function.name) else {
+                if !args.unknown_tools_to_model {
+                    return Err(anyhow::anyhow!(
+                        "Model called an unknown function: {}",
+                        tool_call.function.name
+                    ))
+                    .classify(Failure::Tool);
+                }
+                // the model may well manage without the tool, once it knows
+                info!(
+                    "Model called an unknown function: {}",
+                    tool_call.function.name
+                );
+                let output = format!("Error: tool {} is not available", tool_call.function.name);
+                handles.push(tokio::task::spawn_blocking(move || {
+                    Ok(ToolResult {
+                        output,
+                        is_final: false,
+                    })
+                }));
+                continue;
+            };
+            if !tool.allowed {
+                return Err(anyhow::anyhow!(
+                    "Refusing to run tool {}: {} is not on the allowlist",
+                    tool_call.function.name,
+                    tool.exec_path()?.display()
+                ))
+                .classify(Failure::Tool);
+            }
+            // the arguments are shown before the tool runs, so that a tool that hangs or fails can
+            // be told apart from a model that asked for the wrong thing
+            let arguments =
+                serde_json::from_str::<serde_json::Value>(&tool_call.function.arguments)
+                    .and_then(|arguments| serde_json::to_string_pretty(&arguments))
+                    .unwrap_or_else(|_| tool_call.function.arguments.clone());
+            info!(
+                "Tool {} called with arguments:\n{}",
+                tool_call.function.name, arguments
+            );
+
+            let tool = tool.clone();
+            let tool_call = tool_call.clone();
+            handles.push(tokio::task::spawn_blocking(move || {
+                execute_tool(&tool, &tool_call, tool_timeout)
+            }));
+        }
+        let results = futures_util::future::join_all(handles).await;
+        // a tool that was stopped by the deadline fails the run as such
+        if let Some(deadline) = &backend.deadline {
+            deadline.remaining()?;
+        }
+
+        // messages that tools add on their own go after all the tool results, since the API
+        // expects the results to follow the tool calls directly
+        let mut tool_messages = Vec::new();
+        // the output of the first tool that ended the conversation
+        let mut final_output = None;
+        for (tool_call, result) in tool_calls.iter().zip(results) {
+            let result = result
+                .with_context(|| format!("Tool {} panicked", tool_call.function.name))
+                .classify(Failure::Tool)?;
+            let mut is_final = false;
+            let output = match result {
+                Ok(result) => {
+                    is_final = result.is_final;
+                    result.output
+                }
+                // the model gets to see what went wrong, so that it can retry with different
+                // arguments or give up gracefully
+                Err(e) if args.tool_errors_to_model => {
+                    info!("Tool {} failed: {:#}", tool_call.function.name, e);
+                    format!("Error: {:#}", e)
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Tool {} failed", tool_call.function.name))
+                        .classify(Failure::Tool);
+                }
+            };
+            let output = if tools
+                .get(&tool_call.function.name)
+                .is_some_and(|tool| tool.definition.output == ToolOutput::Messages)
+            {
+                split_tool_messages(output, &mut tool_messages)
+            } else {
+                output
+            };
+            if let Some(limit) = args.tool_output_limit
+                && output.len() > limit
+                && !args.truncate_tool_output
+            {
+                return Err(anyhow::anyhow!(
+                    "Output of tool {} is {} characters long, over the tool output limit of {}",
+                    tool_call.function.name,
+                    output.len(),
+                    limit
+                ))
+                .classify(Failure::Limit);
+            }
+            let output = if args.truncate_tool_output {
+                let remaining = args
+                    .character_limit
+                    .saturating_sub(get_total_size(&request_body.messages));
+                let (budget, limit_name) = match args.tool_output_limit {
+                    Some(limit) if limit < remaining => (limit, "tool output limit"),
+                    _ => (remaining, "character limit"),
+                };
+                let size = output.len();
+                let Some(truncated) = truncate_output(output, budget) else {
+                    return Err(anyhow::anyhow!(
+                        "Output of tool {} is {} characters long, and not even truncated does it \
+                         fit in the {} characters left of the {}",
+                        tool_call.function.name,
+                        size,
+                        budget,
+                        limit_name
+                    ))
+                    .classify(Failure::Limit);
+                };
+                if truncated.len() < size {
+                    warn!(
+                        "Output of tool {} was truncated to fit the {}.",
+                        tool_call.function.name, limit_name
+                    );
+                }
+                truncated
+            } else {
+                output
+            };
+            if args.raw_request {
+                eprintln!("tool:{}", output);
+            }
+            if is_final && final_output.is_none() {
+                info!("Tool {} gave the final answer", tool_call.function.name);
+                final_output = Some(output.clone());
+            }
+
+            request_body.messages.push(Message {
+                role: "tool".to_string(),
+                content: Some(output),
+                images: Vec::new(),
+                tool_calls: None,
+                tool_call_id: Some(tool_call.id.clone()),
+                reasoning: None,
+            });
+
+            // tool results count towards the limits just like any other input
+            check_limits(args, tokenizer, &request_body.messages)
+                .context("Input too long after tool call")?;
+        }
+        if !tool_messages.is_empty() {
+            request_body.messages.append(&mut tool_messages);
+            check_limits(args, tokenizer, &request_body.messages)
+                .context("Input too long after tool call")?;
+        }
+        check_output_limit(
+            args,
+            get_output_size(&request_body.messages[output_start..]),
+        )?;
+
+        // the answer of the tool stands in for the one the model would have given
+        if let Some(content) = final_output {
+            if print_stream {
+                print!("{}", content);
+            }
+            return Ok(Reply {
+                message: Message::new("assistant", content),
+                model,
+                usage,
+                finish_reason: None,
+                id: response_json.id,
+                request_id,
+                alternatives: Vec::new(),
+            });
+        }
+    }
+}
+
+/// Sorts the tool definitions for --tool-order: the named tools come first, in the given order, and
+/// the others follow by name. The order in which the tools were found (which depends on the file
+/// system) doesn't matter then, since it can change how the model picks its tools.
+fn order_tools(definitions: &mut [ToolDefinition], order: &[String]) -> Result<()> {
+    for name in order {
+        if !definitions
+            .iter()
+            .any(|definition| &definition.function.name == name)
+        {
+            return Err(anyhow::anyhow!(
+                "Tool {} is given in --tool-order, but isn't loaded",
+                name
+            ))
+            .classify(Failure::Config);
+        }
+    }
+
+    definitions.sort_by(|a, b| {
+        let key = |definition: &ToolDefinition| {
+            let name = &definition.function.name;
+            let position = order.iter().position(|ordered| ordered == name);
+            (position.unwrap_or(usize::MAX), name.clone())
+        };
+        key(a).cmp(&key(b))
+    });
+    Ok(())
+}
+
+/// Handles the output of a tool with `"output": "messages"`. If it is a JSON array of messages,
+/// the contents of its `tool` messages become the result of the call, and the other messages are
+/// moved to `extra`. Any other output is the result as-is.
+fn split_tool_messages(output: String, extra: &mut Vec<Message>) -> String {
+    let messages: Vec<Message> = match serde_json::from_str(&output) {
+        Ok(messages) => messages,
+        Err(e) => {
+            debug!(
+                "Tool output is not a message array, using it as text: {}",
+                e
+            );
+            return output;
+        }
+    };
+
+    let mut results = Vec::new();
+    for message in messages {
+        if message.role == "tool" {
+            results.extend(message.content);
+        } else {
+            extra.push(message);
+        }
+    }
+    results.join("\n")
+}
+
+/// Whether the answer is rendered with --render-markdown. It only is on a terminal, so that piped
+/// output stays plain markdown; rendering also needs the whole answer, so it isn't streamed.
+fn renders_markdown(args: &Args) -> bool {
+    args.render_markdown && !args.json_output && args.output.is_none() && io::stdout().is_terminal()
+}
+
+/// Whether the answer is changed before it is printed, which can only be done once it is complete,
+/// so that it can't be streamed.
+fn transforms_answer(args: &Args) -> bool {
+    args.strip_thinking || args.postprocess.is_some()
+}
+
+/// Removes the blocks between the given opening and closing tags from an answer, for
+/// --strip-thinking. Chat templates may open the block themselves, so a closing tag without an
+/// opening one ends a block that started with the answer; a block that is never closed (an answer
+/// cut off while thinking) goes on to the end.
+fn remove_thinking(text: &str, tags: &[(&str, &str)]) -> String {
+    let mut text = text.to_string();
+    for &(open, close) in tags {
+        let mut rest = text.as_str();
+        if let Some(end) = rest.find(close)
+            && !rest[..end].contains(open)
+        {
+            rest = &rest[end + close.len()..];
+        }
+
+        let mut stripped = String::new();
+        while let Some(start) = rest.find(open) {
+            stripped.push_str(&rest[..start]);
+            let inside = &rest[start + open.len()..];
+            rest = inside
+                .find(close)
+                .map_or("", |end| &inside[end + close.len()..]);
+        }
+        stripped.push_str(rest);
+
+        // the blocks are usually set apart from the answer by blank lines
+        if stripped.len() != text.len() {
+            text = stripped.trim().to_string();
+        }
+    }
+    text
+}
+
+/// Prints the final assistant message of a completion (unless it was streamed already), and
+/// appends it to the conversation, saving the session if there is one.
+async fn handle_reply(
+    backend: &Backend,
+    args: &Args,
+    request_body: &mut OpenRouterRequest,
+    history_start: usize,
+    reply: Reply,
+) -> Result<()> {
+    let content = reply
+        .message
+        .content
+        .as_deref()
+        .context("Model returned empty content after user input")?;
+
+    // like the prefill below, the thinking only goes from what is printed
+    let thinking_tags: Vec<(&str, &str)> = if args.thinking_tag.is_empty() {
+        vec![("<think>", "</think>")]
+    } else {
+        args.thinking_tag
+            .chunks_exact(2)
+            .map(|tag| (tag[0].as_str(), tag[1].as_str()))
+            .collect()
+    };
+    let strip_thinking = |text: &str| -> String {
+        if args.strip_thinking {
+            remove_thinking(text, &thinking_tags)
+        } else {
+            text.to_string()
+        }
+    };
+    let content = &strip_thinking(content);
+    let alternatives: Vec<String> = reply
+        .alternatives
+        .iter()
+        .map(|text| strip_thinking(text))
+        .collect();
+
+    // providers that don't support response_format silently ignore it
+    if request_body.response_format.is_some() {
+        serde_json::from_str::<serde_json::Value>(content).with_context(|| {
+            format!(
+                "Model did not answer with valid JSON; Answer was: {}",
+                content
+            )
+        })?;
+    }
+
+    // the session keeps the whole answer, only what is printed loses the prefill
+    let prefix = args
+        .prefill
+        .as_deref()
+        .filter(|_| args.assistant_prefix_strip && backend.provider.supports_prefill());
+    let strip = |text: &'_ str| -> String {
+        prefix
+            .and_then(|prefix| text.strip_prefix(prefix))
+            .unwrap_or(text)
+            .to_string()
+    };
+    let content = &strip(content);
+    let alternatives: Vec<String> = alternatives.iter().map(|text| strip(text)).collect();
+
+    // the command gets the answer as it would be printed otherwise
+    let run_postprocess = |text: String| -> Result<String> {
+        let Some(command) = &args.postprocess else {
+            return Ok(text);
+        };
+        match postprocess::postprocess(command, &text) {
+            Ok(output) => Ok(output),
+            Err(e) if args.postprocess_fallback => {
+                warn!("{:#}; printing the answer as it is.", e);
+                Ok(text)
+            }
+            Err(e) => Err(e)
+                .context("Could not postprocess the answer")
+                .classify(Failure::Tool),
+        }
+    };
+    let content = &run_postprocess(content.clone())?;
+    let alternatives = alternatives
+        .into_iter()
+        .map(run_postprocess)
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = if args.json_output {
+        let output = JsonOutput {
+            content,
+            model: &reply.model,
+            usage: reply.usage,
+            finish_reason: reply.finish_reason.as_deref(),
+            id: reply.id.as_deref(),
+            request_id: reply.request_id.as_deref(),
+            choices: if alternatives.is_empty() {
+                Vec::new()
+            } else {
+                std::iter::once(content.as_str())
+                    .chain(alternatives.iter().map(String::as_str))
+                    .collect()
+            },
+        };
+        serde_json::to_string(&output).context("Could not serialize JSON output")? + "\n"
+    } else if !alternatives.is_empty() {
+        // numbered, so that they can be told apart
+        std::iter::once(content.as_str())
+            .chain(alternatives.iter().map(String::as_str))
+            .enumerate()
+            .map(|(index, choice)| format!("[{}]\n{}", index + 1, choice))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    } else {
+        content.to_string()
+    };
+
+    if let Some(path) = &args.output {
+        write_output(path, &output, args.force)?;
+        debug!("Wrote response to {}", path.display());
+    } else if renders_markdown(args) {
+        // with -n, that is every choice under its number
+        termimad::print_text(&output);
+    } else if args.json_output || !request_body.stream || transforms_answer(args) {
+        print!("{}", output);
+    }
+    io::stdout().flush().context("Could not write to stdout")?;
+
+    match reply.finish_reason.as_deref() {
+        Some("length") => warn!("The answer was cut off at the maximum number of tokens"),
+        Some(finish_reason) => debug!("Finish reason: {}", finish_reason),
+        None => debug!("Finish reason was not reported by the API"),
+    }
+
+    match reply.usage {
+        Some(usage) => info!(
+            "Token usage: {} prompt, {} completion, {} total",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+        ),
+        None => info!("Token usage was not reported by the API"),
+    }
+
+    // the pricing may have to be fetched first, so the cost is only estimated when it is shown
+    if args.show_cost || log::log_enabled!(log::Level::Info) {
+        let estimate = match get_cost(backend, &reply).await {
+            Some(cost) => format!("Estimated cost: ${:.6}", cost),
+            None => "Estimated cost: cost unknown".to_string(),
+        };
+        if args.show_cost {
+            eprintln!("{}", estimate);
+        } else {
+            info!("{}", estimate);
+        }
+    }
+
+    request_body.messages.push(reply.message);
+
+    // rewritten after every reply, so that it is complete however the run ends
+    if let Some(path) = &args.transcript {
+        transcript::write_transcript(path, &request_body.messages)
+            .context("Could not write transcript")?;
+    }
+
+    save_session(args, &request_body.messages, history_start)?;
+
+    if let Some(checkpoint) = &args.checkpoint {
+        session::save_checkpoint(checkpoint, &request_body.messages[history_start..])
+            .with_context(|| format!("Could not save checkpoint {}", checkpoint))?;
+    }
+    Ok(())
+}
+
+/// Saves the conversation to the --session, if there is one. The messages before `history_start`
+/// (i.e. the system message) are never saved.
+fn save_session(args: &Args, messages: &[Message], history_start: usize) -> Result<()> {
+    let Some(session) = &args.session else {
+        return Ok(());
+    };
+
+    // the latest exchange starts with the last user message
+    let start = if args.overwrite_session {
+        messages
+            .iter()
+            .rposition(|message| message.role == "user")
+            .unwrap_or(history_start)
+            .max(history_start)
+    } else {
+        history_start
+    };
+    session::save_session(session, &messages[start..])
+        .with_context(|| format!("Could not save session {}", session))
+}
+
+/// Runs `complete`, unless the user interrupts it with Ctrl-C first: then the conversation so far
+/// is saved, and rlm exits.
+async fn complete_or_exit(
+    backend: &Backend,
+    args: &Args,
+    tools: &HashMap<String, Tool>,
+    tokenizer: Option<&CoreBPE>,
+    request_body: &mut OpenRouterRequest,
+    history_start: usize,
+) -> Result<Reply> {
+    let claim = interrupt::claim();
+    let reply = tokio::select! {
+        reply = complete(backend, args, tools, tokenizer, request_body) => Some(reply),
+        () = claim.interrupted() => None,
+    };
+    match reply {
+        Some(reply) => reply,
+        None => exit_interrupted(args, &request_body.messages, history_start),
+    }
+}
+
+/// Flushes the output and saves the session after Ctrl-C, and exits with 130 (128 + SIGINT), like
+/// a process killed by the signal.
+fn exit_interrupted(args: &Args, messages: &[Message], history_start: usize) -> ! {
+    // a streamed answer may not have been flushed completely; it's not part of the session,
+    // since it never became a message
+    io::stdout().flush().ok();
+
+    let end = get_interrupted_end(args, messages, history_start);
+    if let Err(e) = save_session(args, &messages[..end], history_start) {
+        eprintln!("Error: {:#}", e);
+    }
+    std::process::exit(130);
+}
+
+/// Returns how many of the messages of an interrupted completion are saved. It may leave messages
+/// behind that can't be sent again: the parts of an answer cut off so far (with --continue, each
+/// followed by the request to continue), a prefill (which is always the last message while it is
+/// in the request), or tool calls whose results are missing.
+fn get_interrupted_end(args: &Args, messages: &[Message], history_start: usize) -> usize {
+    let mut end = messages.len();
+    while end >= history_start + 2
+        && messages[end - 1].role == "user"
+        && messages[end - 1].content.as_deref() == Some(CONTINUE_PROMPT)
+        && messages[end - 2].role == "assistant"
+    {
+        end -= 2;
+    }
+    if let Some(prefill) = &args.prefill
+        && end > history_start
+        && messages[end - 1].role == "assistant"
+        && messages[end - 1].content.as_ref() == Some(prefill)
+    {
+        end -= 1;
+    }
+    if let Some(last_call) = messages[..end]
+        .iter()
+        .rposition(|message| message.tool_calls.is_some())
+    {
+        let calls = messages[last_call].tool_calls.as_ref().map_or(0, Vec::len);
+        let results = messages[last_call + 1..end]
+            .iter()
+            .filter(|message| message.role == "tool")
+            .count();
+        if results < calls {
+            end = last_call;
+        }
+    }
+    end.max(history_start)
+}
+
+/// Writes the response to the file given with --output. Existing files are only replaced when
+/// `force` is set.
+fn write_output(path: &Path, output: &str, force: bool) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => anyhow::anyhow!(
+            "File {} already exists, use --force to overwrite it",
+            path.display()
+        ),
+        _ => anyhow::Error::new(e).context(format!("Could not open file {}", path.display())),
+    })?;
+    file.write_all(output.as_bytes())
+        .with_context(|| format!("Could not write to file {}", path.display()))
+}
+
+/// Checks --model and every --fallback-model against the list of OpenRouter models, to catch
+/// typos before a request fails. Without a list (e.g. when offline), nothing is checked.
+async fn validate_models(backend: &Backend, args: &Args) -> Result<()> {
+    let models = match models::get_models(&backend.client, backend.api_key.as_deref()).await {
+        Ok(models) => models,
+        Err(e) => {
+            debug!(
+                "Could not get the list of models, not validating them: {:#}",
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    for model in std::iter::once(&args.model).chain(&args.fallback_model) {
+        models::check_model(&models, model)?;
+    }
+    Ok(())
+}
+
+/// Estimates the cost of a reply from its token usage and the pricing of its model. Returns `None`
+/// if either is unavailable.
+async fn get_cost(backend: &Backend, reply: &Reply) -> Option<f64> {
+    // the pricing is only known for OpenRouter models
+    if !backend.is_openrouter() {
+        return None;
+    }
+
+    let usage = reply.usage?;
+    let models = models::get_models(&backend.client, backend.api_key.as_deref())
+        .await
+        .ok()?;
+
+    let pricing = models
+        .iter()
+        .find(|model| model.id == reply.model)?
+        .pricing
+        .as_ref()?;
+    pricing.cost(usage.prompt_tokens, usage.completion_tokens)
+}
+
+/// Sends the request to the API, and returns the response once its status is successful.
+/// Rate limits (429) and server errors (5xx) are retried with exponential backoff, any other
+/// status fails immediately.
+async fn send_request(
+    backend: &Backend,
+    request_body: &OpenRouterRequest,
+    args: &Args,
+) -> Result<reqwest::Response> {
+    let url = backend.chat_url();
+    let body = backend.provider.request_body(request_body);
+    // retries send the same body again, so it is only printed once
+    if args.verbose_request_body {
+        eprintln!(
+            "Request body:\n{}",
+            serde_json::to_string_pretty(&body).context("Could not serialize request")?
+        );
+    }
+    let body = serde_json::to_vec(&body).context("Could not serialize request")?;
+    let mut compressed = if args.compress {
+        Some(gzip(&body).context("Could not compress request")?)
+    } else {
+        None
+    };
+
+    let mut attempt = 0;
+    // with --resume-on-rate-limit, the time waited for rate limits so far, and how often
+    let mut waited = Duration::ZERO;
+    let mut waits = 0;
+    loop {
+        let mut request = backend
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json");
+        request = match &compressed {
+            Some(compressed) => request
+                .header("Content-Encoding", "gzip")
+                .body(compressed.clone()),
+            None => request.body(body.clone()),
+        };
+        match &backend.api_key {
+            Some(api_key) if backend.provider == Provider::Anthropic => {
+                request = request.header("x-api-key", api_key);
+            }
+            Some(api_key) => {
+                request = request.header("Authorization", format!("Bearer {}", api_key));
+            }
+            None => {}
+        }
+        if backend.provider == Provider::Anthropic {
+            request = request.header("anthropic-version", anthropic::API_VERSION);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| network_error(e, "Failed to send API request", args.timeout))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        // not every server accepts compressed bodies; those that say so get the body as it is
+        if status == StatusCode::UNSUPPORTED_MEDIA_TYPE && compressed.is_some() {
+            warn!("The API does not accept compressed requests, sending it uncompressed");
+            compressed = None;
+            continue;
+        }
+
+        let mut retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+        // waits for rate limits don't count as retries, but are capped in total
+        if status == StatusCode::TOO_MANY_REQUESTS && args.resume_on_rate_limit {
+            let wait = get_rate_limit_reset(&response).unwrap_or_else(|| {
+                jitter(Duration::from_millis(
+                    args.retry_delay.saturating_mul(1 << waits.min(16)),
+                ))
+            });
+            if waited + wait <= Duration::from_secs(args.max_rate_limit_wait) {
+                wait_for_rate_limit(wait).await;
+                waited += wait;
+                waits += 1;
+                continue;
+            }
+            warn!(
+                "The rate limit resets in {:.0} seconds, which is beyond --max-rate-limit-wait; giving up.",
+                wait.as_secs_f64()
+            );
+            retryable = false;
+        }
+        if !retryable || attempt >= args.retries {
+            let request_id = get_request_id(&response);
+            log_rate_limits(&response);
+            let response_text = response
+                .text()
+                .await
+                .map_err(|e| network_error(e, "Failed to read API response", args.timeout))?;
+            return Err(StatusError {
+                status,
+                body: response_text,
+                request_id,
+            }
+            .into());
+        }
+
+        // the server knows best when it is going to accept requests again
+        let delay = get_retry_after(&response).unwrap_or_else(|| {
+            jitter(Duration::from_millis(
+                args.retry_delay.saturating_mul(1 << attempt.min(16)),
+            ))
+        });
+        info!(
+            "API responded with status {}; retrying in {:.1} seconds ({}/{})",
+            status,
+            delay.as_secs_f64(),
+            attempt + 1,
+            args.retries
+        );
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Whether a response body that could not be parsed ends before the JSON in it does, which means
+/// it was cut off on the way rather than being a complete answer of an unexpected shape.
+fn is_truncated(response_text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(response_text).is_err_and(|e| e.is_eof())
+}
+
+/// Randomizes a retry delay to between half and all of it, so that clients that failed at the same
+/// time don't all retry at the same time again.
+fn jitter(delay: Duration) -> Duration {
+    // the hasher is seeded randomly, which is all the randomness needed here
+    let random =
+        std::hash::BuildHasher::hash_one(&std::collections::hash_map::RandomState::new(), 0);
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+    delay.mul_f64(fraction)
+}
+
+/// Compresses a request body for --compress.
+fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// An unsuccessful response of the API, after all retries.
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+    body: String,
+    request_id: Option<String>,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "API responeded with status {}; Response body was: {}",
+            self.status, self.body
+        )?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, "; Request id was: {}", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// What the errors for a model that doesn't exist (anymore) say, e.g. OpenRouter's "No endpoints
+/// found for foo/bar." and "foo/bar is not a valid model ID", or OpenAI's "model_not_found" code.
+const MODEL_NOT_FOUND_ERRORS: &[&str] = &[
+    "no endpoints found",
+    "not a valid model",
+    "model_not_found",
+    "model not found",
+];
+
+impl StatusError {
+    /// Whether the error is about the model rather than the request: it doesn't exist (anymore),
+    /// or none of its providers can serve it right now. Other errors that mention the model (say,
+    /// that it doesn't support tools) are about the request, and another model won't fix them.
+    fn is_model_unavailable(&self) -> bool {
+        match self.status.as_u16() {
+            // rate limited upstream, down, or overloaded (529 is Anthropic's "overloaded")
+            429 | 502 | 503 | 529 => true,
+            400 | 404 => {
+                let body = self.body.to_lowercase();
+                MODEL_NOT_FOUND_ERRORS
+                    .iter()
+                    .any(|error| body.contains(error))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Sends the request like `send_request`, but moves on to the next --fallback-model when the
+/// current model is unavailable. The model that worked stays in the request body, so later
+/// requests of the conversation don't try the unavailable ones again.
+async fn send_with_fallback(
+    backend: &Backend,
+    request_body: &mut OpenRouterRequest,
+    args: &Args,
+) -> Result<reqwest::Response> {
+    loop {
+        let error = match send_request(backend, request_body, args).await {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+
+        let Some(status_error) = error
+            .downcast_ref::<StatusError>()
+            .filter(|e| e.is_model_unavailable())
+        else {
+            return Err(error);
+        };
+        let models: Vec<&String> = std::iter::once(&args.model)
+            .chain(&args.fallback_model)
+            .collect();
+        let next = models
+            .iter()
+            .position(|&model| *model == request_body.model)
+            .and_then(|index| models.get(index + 1));
+
+        match next {
+            Some(&next) => {
+                warn!(
+                    "Model {} is unavailable (status {}), falling back to {}",
+                    request_body.model, status_error.status, next
+                );
+                request_body.model = next.clone();
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+/// Wraps a reqwest error, telling timeouts apart from other network failures.
+fn network_error(e: reqwest::Error, action: &str, timeout: u64) -> anyhow::Error {
+    let message = if e.is_timeout() {
+        format!("API request timed out after {} seconds", timeout)
+    } else {
+        action.to_string()
+    };
+    anyhow::Error::new(e).context(message)
+}
+
+/// Returns the x-request-id header of a response, which identifies the call in bug reports.
+fn get_request_id(response: &reqwest::Response) -> Option<String> {
+    let value = response.headers().get("x-request-id")?.to_str().ok()?;
+    Some(value.to_string())
+}
+
+/// Logs the rate limits the API reports in the x-ratelimit-* headers (at the debug level), so that
+/// users can see a 429 coming. Providers name them differently, and most send only some of them.
+fn log_rate_limits(response: &reqwest::Response) {
+    for (name, value) in response.headers() {
+        let Some(limit) = name.as_str().strip_prefix("x-ratelimit-") else {
+            continue;
+        };
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        debug!("{}: {}", name, describe_rate_limit(limit, value));
+    }
+}
+
+/// OpenRouter gives the reset time as a timestamp in milliseconds, which is shown as the time left
+/// until then; every other value is shown as it is.
+fn describe_rate_limit(limit: &str, value: &str) -> String {
+    if limit.starts_with("reset")
+        && let Ok(reset) = value.parse::<u64>()
+        && let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        && reset > 1_000_000_000_000
+    {
+        let seconds = reset.saturating_sub(now.as_millis() as u64) as f64 / 1000.0;
+        return format!("in {:.1} seconds", seconds);
+    }
+    value.to_string()
+}
+
+/// Parses the Retry-After header of a response. Only the delay-seconds form is supported, since
+/// that is what APIs send in practice.
+fn get_retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    let seconds = value.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Returns how long it takes the rate limit of a response to reset: the Retry-After header, or
+/// else the x-ratelimit-reset header, which is a timestamp (in milliseconds with OpenRouter, in
+/// seconds elsewhere) or a number of seconds.
+fn get_rate_limit_reset(response: &reqwest::Response) -> Option<Duration> {
+    if let Some(delay) = get_retry_after(response) {
+        return Some(delay);
+    }
+    let value = response.headers().get("x-ratelimit-reset")?.to_str().ok()?;
+    let value = value.trim().parse::<u64>().ok()?;
+    let reset = if value > 1_000_000_000_000 {
+        Duration::from_millis(value)
+    } else if value > 1_000_000_000 {
+        Duration::from_secs(value)
+    } else {
+        return Some(Duration::from_secs(value));
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(reset.saturating_sub(now))
+}
+
+/// Sleeps until a rate limit has reset. In verbose mode, a terminal gets a countdown on stderr.
+async fn wait_for_rate_limit(wait: Duration) {
+    if !log::log_enabled!(log::Level::Info) || !io::stderr().is_terminal() {
+        info!(
+            "Rate limited; resuming in {:.1} seconds",
+            wait.as_secs_f64()
+        );
+        tokio::time::sleep(wait).await;
+        return;
+    }
+
+    let end = Instant::now() + wait;
+    while let Some(left) = end
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+    {
+        eprint!(
+            "\rRate limited; resuming in {} seconds ",
+            left.as_secs_f64().ceil()
+        );
+        tokio::time::sleep(left.min(Duration::from_secs(1))).await;
+    }
+    // the countdown is cleared, so that it doesn't mix with what comes next
+    eprint!("\r\x1b[K");
+}
+
+/// Builds the HTTP client, with the timeout, the proxy and the headers of the options.
+fn build_client(args: &Args) -> Result<reqwest::Client> {
+    // app attribution, see https://openrouter.ai/docs/api-reference/overview#headers
+    let mut headers = HeaderMap::new();
+    for (name, value) in [("HTTP-Referer", &args.referer), ("X-Title", &args.title)] {
+        if let Some(value) = value {
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid {} header value: {}", name, value))
+                .classify(Failure::Config)?;
+            headers.insert(name, value);
+        }
+    }
+
+    // the timeout covers the whole request, including reading the (possibly streamed) response
+    let mut client = reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(args.timeout))
+        // the one client serves every turn of a run, so its pooled connection is reused after the
+        // tools ran; keepalive probes keep it from being dropped by middleboxes in the meantime
+        .tcp_keepalive(Duration::from_secs(60));
+    // without --proxy, reqwest uses HTTP_PROXY, HTTPS_PROXY and NO_PROXY from the environment
+    if let Some(proxy) = &args.proxy {
+        // reqwest would quietly turn anything that doesn't parse into http://<anything>
+        let url = reqwest::Url::parse(proxy)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+            .with_context(|| {
+                format!(
+                    "Invalid proxy URL: {} (expected http://host:port or https://host:port)",
+                    proxy
+                )
+            })
+            .classify(Failure::Config)?;
+        let proxy = reqwest::Proxy::all(url)
+            .with_context(|| format!("Invalid proxy URL: {}", proxy))
+            .classify(Failure::Config)?;
+        client = client.proxy(proxy);
+    }
+    client
+        .build()
+        .context("Could not create HTTP client")
+        .classify(Failure::Config)
+}
+
+/// Formats a price per token (as OpenRouter sends it) as dollars per million tokens.
+fn format_price(price: &str) -> String {
+    match price.parse::<f64>() {
+        Ok(price) => format!("${:.2}/M", price * 1_000_000.0),
+        Err(_) => "?".to_string(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // the matches are kept around, so that the config file can tell which options were given on
+    // the command line
+    let matches = Args::command().get_matches();
+    interrupt::install();
+    let json_errors = matches.get_flag("json_errors");
+    let watch: Vec<PathBuf> = matches
+        .get_many("watch")
+        .map(|paths| paths.cloned().collect())
+        .unwrap_or_default();
+
+    if watch.is_empty() {
+        match run(matches).await {
+            Ok(false) => {}
+            // scripts can't tell a cut-off answer from a complete one by the output alone
+            Ok(true) => std::process::exit(EXIT_TRUNCATED),
+            // printed like returning the error from main would, but with an exit status that tells
+            // the class of the failure
+            Err(e) => {
+                report_error(&e, json_errors);
+                std::process::exit(failure::exit_code(&e));
+            }
+        }
+        return;
+    }
+
+    // with --watch, everything is done again on every change, and a failed run just waits for
+    // the next one; stdin would be gone after the first run, though
+    let watcher = if ["message", "prompt", "prompt_file"]
+        .iter()
+        .any(|id| matches.contains_id(id))
+    {
+        watch::watch(&watch)
+    } else {
+        Err(anyhow::anyhow!(
+            "--watch needs the user message as an argument, with -p or with --prompt-file"
+        ))
+    };
+    let mut watcher = watcher.classify(Failure::Config).unwrap_or_else(|e| {
+        report_error(&e, json_errors);
+        std::process::exit(failure::exit_code(&e));
+    });
+    loop {
+        match run(matches.clone()).await {
+            Ok(false) => {}
+            Ok(true) => warn!(
+                "The run ended with a cut-off answer (exit status 3); waiting for the next change."
+            ),
+            Err(e) => report_error(&e, json_errors),
+        }
+        watcher.changed().await;
+        println!();
+    }
+}
+
+fn report_error(e: &anyhow::Error, json_errors: bool) {
+    if json_errors {
+        eprintln!("{}", failure::to_json(e));
+    } else {
+        eprintln!("Error: {:?}", e);
+    }
+}
+
+/// Does everything a single invocation does. Returns whether the answer was cut off at the maximum
+/// number of tokens, which `main` turns into an exit status.
+async fn run(matches: ArgMatches) -> Result<bool> {
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     if args.license {
         println!("GNU LGPLv3+");
-        return Ok(());
+        return Ok(false);
+    }
+
+    if args.list_prompts {
+        for name in list_prompts().context("Could not list prompts")? {
+            println!("{}", name);
+        }
+        return Ok(false);
     }
 
-    if args.verbose {
-        eprintln!("rlm started");
+    if let Some(session) = &args.clear_session {
+        session::clear_session(session)
+            .with_context(|| format!("Could not clear session {}", session))?;
+        return Ok(false);
     }
 
-    let api_key = get_api_key().context("Could not retrieve OpenRouter API key")?;
-    if args.verbose {
-        eprintln!("Read OpenRouter API key.");
+    let config = config::load_config()
+        .context("Could not load config file")
+        .classify(Failure::Config)?;
+    config.apply(&mut args, &matches);
+
+    // "ollama/llama3.1" is obvious enough to not need --provider ollama as well
+    if args.provider == Provider::Openrouter
+        && matches.value_source("provider") != Some(ValueSource::CommandLine)
+        && let Some(model) = args.model.strip_prefix("ollama/")
+    {
+        args.model = model.to_string();
+        args.provider = Provider::Ollama;
+    }
+
+    logging::init(&args)?;
+    debug!("rlm started");
+
+    if args.list_models {
+        // the list is public, but it is requested like the completions are, with the key if
+        // there is one
+        let client = build_client(&args)?;
+        let api_key = get_provider_api_key(Provider::Openrouter, true)?;
+        let models = models::get_models(&client, api_key.as_deref())
+            .await
+            .context("Could not get the list of models")?;
+        if args.json_output {
+            println!(
+                "{}",
+                serde_json::to_string(&models).context("Could not serialize JSON output")?
+            );
+            return Ok(false);
+        }
+        // one model per line, so the ids are easy to cut out; the prices are per million tokens
+        for info in &models {
+            let context_length = info
+                .context_length
+                .map_or("?".to_string(), |length| length.to_string());
+            let (prompt, completion) = match &info.pricing {
+                Some(pricing) => (
+                    format_price(&pricing.prompt),
+                    format_price(&pricing.completion),
+                ),
+                None => ("?".to_string(), "?".to_string()),
+            };
+            println!(
+                "{:<50} {:>8} {:>10} {:>10}",
+                info.id, context_length, prompt, completion
+            );
+        }
+        return Ok(false);
     }
 
+    // no point in reading any input, when the request would be rejected anyway
+    check_range("--temperature", args.temperature, 0.0..=2.0)?;
+    check_range("--top-p", args.top_p, 0.0..=1.0)?;
+    check_range("--min-p", args.min_p, 0.0..=1.0)?;
+    check_range("--presence-penalty", args.presence_penalty, -2.0..=2.0)?;
+    check_range("--frequency-penalty", args.frequency_penalty, -2.0..=2.0)?;
+
     let mut request_body = OpenRouterRequest {
-        model: String::from(args.model),
+        model: args.model.clone(),
         messages: Vec::<Message>::new(),
+        tools: None,
+        stream: args.stream && args.provider.supports_streaming(),
+        max_tokens: args.max_tokens,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        min_p: args.min_p,
+        presence_penalty: args.presence_penalty,
+        frequency_penalty: args.frequency_penalty,
+        stop: args.stop.clone(),
+        seed: args.seed,
+        logit_bias: get_logit_bias(&args)?,
+        response_format: get_response_format(&args)?,
+        reasoning: args.reasoning_effort.map(|effort| Reasoning { effort }),
+        n: args.num_completions.filter(|&n| n > 1),
+        user: args.user.clone(),
+        metadata: get_metadata(&args)?,
     };
+    if args.stream && !request_body.stream {
+        warn!(
+            "--stream is not supported with --provider {}, ignoring it.",
+            args.provider.name()
+        );
+    }
+    if request_body.n.is_some() && !args.provider.supports_openai_fields() {
+        warn!(
+            "--num-completions is not supported with --provider {}, ignoring it.",
+            args.provider.name()
+        );
+        request_body.n = None;
+    }
+    // the choices would arrive interleaved
+    if request_body.n.is_some() && request_body.stream {
+        warn!("--stream is not supported with --num-completions, ignoring it.");
+        request_body.stream = false;
+    }
+    if args.prefill.is_some() && !args.provider.supports_prefill() {
+        warn!(
+            "--prefill is not supported with --provider {}, ignoring it.",
+            args.provider.name()
+        );
+    }
+    if !request_body.logit_bias.is_empty() && !args.provider.supports_openai_fields() {
+        warn!(
+            "--logit-bias is not supported with --provider {}, ignoring it.",
+            args.provider.name()
+        );
+    }
+    if !request_body.metadata.is_empty() && !args.provider.supports_openai_fields() {
+        warn!(
+            "--metadata is not supported with --provider {}, ignoring it.",
+            args.provider.name()
+        );
+    }
+    // the answer is still checked, but nothing asks the model for JSON
+    if request_body.response_format.is_some() && args.provider == Provider::Anthropic {
+        warn!(
+            "--json and --json-schema are not supported with --provider anthropic, only the answer is checked."
+        );
+    }
+    // they are sent anyway, since providers that don't know them just drop them
+    if (args.top_k.is_some() || args.min_p.is_some()) && ignores_open_sampling(&args) {
+        warn!("OpenAI models ignore --top-k and --min-p.");
+    }
 
-    let user_message = get_user_message().context("Could not get user message")?;
+    let allowlist = args
+        .allowed_tools
+        .as_deref()
+        .map(tools::load_allowlist)
+        .transpose()
+        .context("Could not load the allowlist of tools")
+        .classify(Failure::Config)?;
 
-    if args.verbose {
-        eprintln!(
+    // maps function names to the tools that implement them
+    let mut tools = HashMap::<String, Tool>::new();
+    let mut loaded = Vec::<(PathBuf, ToolDefinition)>::new();
+    for tool_dir in &args.tool_dir {
+        let definition = load_tool_definition(tool_dir)
+            .with_context(|| format!("Could not load tool from {}", tool_dir.display()))
+            .classify(Failure::Tool)?;
+        loaded.push((tool_dir.clone(), definition));
+    }
+    for tools_dir in &args.tools_dir {
+        loaded.extend(discover_tools(tools_dir).classify(Failure::Tool)?);
+    }
+    for manifest in &args.tool_manifest {
+        loaded.extend(
+            load_tool_manifest(manifest)
+                .context("Could not load tool manifest")
+                .classify(Failure::Tool)?,
+        );
+    }
+
+    for (tool_dir, definition) in loaded {
+        let name = definition.function.name.clone();
+
+        request_body
+            .tools
+            .get_or_insert_with(Vec::new)
+            .push(definition.clone());
+
+        // two tools with the same name would make the dispatch ambiguous
+        debug!("Loaded tool {} from {}", name, tool_dir.display());
+        let mut tool = Tool {
+            dir: tool_dir,
+            definition,
+            allowed: true,
+        };
+        if let Some(allowlist) = &allowlist {
+            tool.allowed = tools::is_allowed(&tool, allowlist).classify(Failure::Tool)?;
+            if !tool.allowed {
+                debug!("The exec of tool {} is not on the allowlist", name);
+            }
+        }
+        if tools.insert(name.clone(), tool).is_some() {
+            return Err(anyhow::anyhow!("Tool {} is defined more than once", name))
+                .classify(Failure::Tool);
+        }
+    }
+    if let Some(definitions) = &mut request_body.tools {
+        order_tools(definitions, &args.tool_order)?;
+    } else if !args.tool_order.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--tool-order is given, but no tools are loaded"
+        ))
+        .classify(Failure::Config);
+    }
+
+    let has_message_arg =
+        args.message.is_some() || args.prompt.is_some() || args.prompt_file.is_some();
+
+    // stdin can only be read once, so with "--system -" nothing else may need it
+    let system_from_stdin = args.system.iter().filter(|s| s.trim() == "-").count();
+    if system_from_stdin > 1 {
+        return Err(anyhow::anyhow!("--system - can only be given once")).classify(Failure::Config);
+    }
+    if system_from_stdin == 1 && (!has_message_arg || args.interactive || args.history_stdin) {
+        return Err(anyhow::anyhow!(
+            "--system - reads the system prompt from stdin, so the user message has to be given with -p, as an argument or with --prompt-file (and --interactive and --history-stdin can't be used)"
+        ))
+        .classify(Failure::Config);
+    }
+
+    // retrieve system message; every --system is resolved on its own, so that prompt fragments
+    // from different sources can be composed
+    let vars = get_template_vars(&args)?;
+    let mut system_parts = Vec::<String>::new();
+    for system_message_arg in &args.system {
+        let system_message = get_system_message(system_message_arg.trim(), &vars)
+            .context("Could not get system message")?;
+        debug!(
+            "Read system message:\n\n```\n{}\n```\n\n...of size {}",
+            &system_message,
+            system_message.len()
+        );
+        system_parts.push(system_message.trim().to_string());
+    }
+
+    if !system_parts.is_empty() {
+        // push message into the message list
+        request_body
+            .messages
+            .push(Message::new("system", system_parts.join("\n\n")));
+    }
+
+    if let Some(path) = &args.examples {
+        let examples = get_examples(path).context("Could not load examples")?;
+        debug!("Loaded {} example messages", examples.len());
+        request_body.messages.extend(examples);
+    }
+
+    // the system message (and the examples) are resolved anew on every run, so they are not a
+    // part of the session: only the messages after them are stored
+    let history_start = request_body.messages.len();
+
+    // checked before anything is sent, so that an answer isn't lost to a bad name
+    if let Some(checkpoint) = &args.checkpoint {
+        session::get_checkpoint_path(checkpoint)?;
+    }
+
+    // a conversation started from a checkpoint branches off: the session (if any) goes on from
+    // the checkpoint, so it has to be a new one, unless replacing it was asked for
+    if let Some(checkpoint) = &args.from_checkpoint
+        && let Some(session) = &args.session
+        && !args.overwrite_session
+        && !session::load_session(session)
+            .with_context(|| format!("Could not load session {}", session))?
+            .is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "Session {} already has messages, which starting from checkpoint {} would replace; use a new session, or --overwrite-session to replace them",
+            session,
+            checkpoint
+        ))
+        .classify(Failure::Config);
+    }
+    if let Some(checkpoint) = &args.from_checkpoint {
+        let history = session::load_checkpoint(checkpoint)
+            .with_context(|| format!("Could not load checkpoint {}", checkpoint))?;
+        debug!(
+            "Loaded {} messages from checkpoint {}",
+            history.len(),
+            checkpoint
+        );
+        request_body.messages.extend(history);
+    } else if let Some(session) = &args.session {
+        let history = session::load_session(session)
+            .with_context(|| format!("Could not load session {}", session))?;
+        debug!("Loaded {} messages from session {}", history.len(), session);
+        request_body.messages.extend(history);
+    }
+
+    let mut images = Vec::<String>::new();
+    for image in &args.image {
+        images.push(images::load_image(image).context("Could not load image")?);
+    }
+    let mut attachments = Vec::<String>::new();
+    for path in &args.attach {
+        attachments.push(
+            attachments::load_attachment(path, args.attach_base64)
+                .context("Could not load attachment")?,
+        );
+    }
+
+    // in interactive mode, the user messages are read from stdin line by line later on; a message
+    // given on the command line is still sent first
+
+    // with --history-stdin (or --history-file), stdin (or the file) holds the conversation, and a
+    // user message is only added when one is given on the command line
+    let has_history = args.history_stdin || args.history_file.is_some();
+    if args.history_stdin {
+        let history = get_history_from_stdin(&args)?;
+        debug!("Read {} messages from stdin", history.len());
+        request_body.messages.extend(history);
+    }
+    if let Some(path) = &args.history_file {
+        let history = get_history_from_file(path, &args)?;
+        debug!("Read {} messages from {}", history.len(), path.display());
+        request_body.messages.extend(history);
+    }
+    if has_history && !has_message_arg && !images.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--image needs a user message on the command line when --history-stdin or --history-file is given"
+        ));
+    }
+    if has_history && !has_message_arg && !attachments.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--attach needs a user message on the command line when --history-stdin or --history-file is given"
+        ));
+    }
+
+    // the messages of the current turn start here, after the system message and the history
+    let prompt_start = request_body.messages.len();
+    if (!args.interactive && !has_history) || has_message_arg {
+        let user_message = get_user_message(&args).context("Could not get user message")?;
+
+        debug!(
             "Read user message:\n\n```\n{}\n```\n\n...of size {}",
             &user_message,
             user_message.len()
         );
+        let mut message = Message::new("user", attach(user_message, &mut attachments));
+        message.images = std::mem::take(&mut images);
+        request_body.messages.push(message);
     }
-    request_body.messages.push(Message {
-        role: "user".to_string(),
-        content: user_message,
-    });
 
-    // retrieve system message
-    if let Some(system_message_arg) = args.system {
-        let system_message = get_system_message(system_message_arg.trim())
-            .context("Could not get system message")?;
-        if args.verbose {
+    if args.raw_request {
+        for message in &request_body.messages {
             eprintln!(
-                "Read system message:\n\n```\n{}\n```\n\n...of size {}",
-                &system_message,
-                system_message.len()
+                "{}:{}",
+                message.role,
+                message.content.as_deref().unwrap_or_default()
             );
         }
+    }
 
-        // push message into the message list
-        request_body.messages.push(Message {
-            role: "system".to_string(),
-            content: system_message,
-        });
+    // the tokenizer is only loaded when it is needed, since it takes a moment
+    let tokenizer = if args.token_limit.is_some() || args.count_tokens {
+        Some(tokens::get_tokenizer(&request_body.model)?)
+    } else {
+        None
+    };
+
+    if let Some(tokenizer) = &tokenizer
+        && args.count_tokens
+    {
+        println!(
+            "{}",
+            tokens::count_tokens(tokenizer, &request_body.messages)
+        );
+        return Ok(false);
     }
 
-    if args.raw_request {
-        let mut size = 0;
-        for message in &request_body.messages {
-            eprintln!("{}:{}", message.role, message.content);
-            size += message.content.len();
+    // fail before paying for a response that can't be written
+    if let Some(path) = &args.output
+        && path.exists()
+        && !args.force
+    {
+        return Err(anyhow::anyhow!(
+            "File {} already exists, use --force to overwrite it",
+            path.display()
+        ));
+    }
+
+    if args.echo_input {
+        echo_input(&request_body.messages, args.output.is_none());
+    }
+
+    if args.dry_run {
+        let body = args.provider.request_body(&request_body);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&body).context("Could not serialize request")?
+        );
+        return Ok(false);
+    }
+
+    // servers given with --base-url may not need a key
+    let api_key = get_provider_api_key(args.provider, args.base_url.is_some())?;
+
+    let backend = Backend {
+        client: build_client(&args)?,
+        api_key,
+        provider: args.provider,
+        base_url: args
+            .base_url
+            .clone()
+            .unwrap_or_else(|| args.provider.default_base_url().to_string()),
+        chat_path: args
+            .chat_path
+            .clone()
+            .unwrap_or_else(|| args.provider.chat_path().to_string()),
+        cache: args
+            .cache_dir
+            .clone()
+            .filter(|_| !args.no_cache)
+            .map(|dir| Cache {
+                dir,
+                ttl: args.cache_ttl.map(Duration::from_secs),
+            }),
+        deadline: args.deadline.map(Deadline::new),
+    };
+    if backend.cache.is_some() && request_body.stream {
+        debug!("Streamed responses are not cached");
+    }
+
+    if !args.no_validate_model && backend.is_openrouter() {
+        validate_models(&backend, &args).await?;
+    }
+
+    if args.interactive {
+        if has_message_arg {
+            check_prompt_size(&args, &request_body.messages[prompt_start..])?;
+            check_limits(&args, tokenizer.as_ref(), &request_body.messages)?;
+            let reply = complete_or_exit(
+                &backend,
+                &args,
+                &tools,
+                tokenizer.as_ref(),
+                &mut request_body,
+                history_start,
+            )
+            .await?;
+            handle_reply(&backend, &args, &mut request_body, history_start, reply).await?;
+            println!();
         }
-        if size == 0 {
-            return Err(anyhow::anyhow!("Input is empty"));
+
+        // the prompt is only useful to humans, scripts feeding us lines don't need it
+        let show_prompt = io::stdin().is_terminal() && !args.quiet;
+
+        loop {
+            if show_prompt {
+                eprint!("> ");
+                io::stderr().flush().context("Could not write to stderr")?;
+            }
+
+            // the line is read on a blocking thread, so that Ctrl-C can still end rlm while it waits
+            let claim = interrupt::claim();
+            let read_line = tokio::task::spawn_blocking(|| {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line).map(|read| (read, line))
+            });
+            let (read, line) = tokio::select! {
+                read = read_line => read
+                    .context("Could not read from stdin")?
+                    .context("Could not read from stdin")?,
+                () = claim.interrupted() => {
+                    exit_interrupted(&args, &request_body.messages, history_start)
+                }
+            };
+            drop(claim);
+            if read == 0 {
+                // Ctrl-D
+                return Ok(false);
+            }
+
+            if line.trim().is_empty() {
+                continue;
+            }
+            // the line break only ends the message, it isn't part of it
+            let line = if args.no_trim {
+                line.trim_end_matches(['\n', '\r'])
+            } else {
+                line.trim()
+            };
+            let mut message = Message::new("user", attach(line.to_string(), &mut attachments));
+            message.images = std::mem::take(&mut images); // only the first message gets them
+            check_prompt_size(&args, std::slice::from_ref(&message))?;
+            request_body.messages.push(message);
+            check_limits(&args, tokenizer.as_ref(), &request_body.messages)?;
+
+            let reply = complete_or_exit(
+                &backend,
+                &args,
+                &tools,
+                tokenizer.as_ref(),
+                &mut request_body,
+                history_start,
+            )
+            .await?;
+            handle_reply(&backend, &args, &mut request_body, history_start, reply).await?;
+
+            // replies rarely end in a newline, and the next prompt shouldn't stick to them
+            println!();
         }
-        if size > args.character_limit {
-            return Err(anyhow::anyhow!(format!(
-                "Input too long: {} characters given, but the limit is {}",
-                size, args.character_limit
-            )));
+    }
+
+    check_prompt_size(&args, &request_body.messages[prompt_start..])?;
+    if check_limits(&args, tokenizer.as_ref(), &request_body.messages)? == 0 {
+        return Err(anyhow::anyhow!("Input is empty"));
+    }
+
+    let reply = complete_or_exit(
+        &backend,
+        &args,
+        &tools,
+        tokenizer.as_ref(),
+        &mut request_body,
+        history_start,
+    )
+    .await?;
+    let truncated = reply.finish_reason.as_deref() == Some("length");
+    handle_reply(&backend, &args, &mut request_body, history_start, reply).await?;
+
+    Ok(truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THINK: &[(&str, &str)] = &[("<think>", "</think>")];
+
+    fn args(command_line: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("rlm").chain(command_line.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn unset_max_tokens_is_not_serialized() {
+        let request = OpenRouterRequest {
+            model: "openai/gpt-4o".to_string(),
+            messages: vec![Message::new("user", "Hi".to_string())],
+            tools: None,
+            stream: false,
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            min_p: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            stop: Vec::new(),
+            seed: None,
+            logit_bias: BTreeMap::new(),
+            response_format: None,
+            reasoning: None,
+            n: None,
+            user: None,
+            metadata: BTreeMap::new(),
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("max_tokens").is_none());
+
+        let request = OpenRouterRequest {
+            max_tokens: Some(100),
+            ..request
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["max_tokens"], 100);
+    }
+
+    #[test]
+    fn remove_thinking_strips_blocks() {
+        assert_eq!(
+            remove_thinking("<think>hmm</think>\n\nAnswer", THINK),
+            "Answer"
+        );
+        assert_eq!(
+            remove_thinking("<think>a</think>One <think>b</think>two", THINK),
+            "One two"
+        );
+    }
+
+    #[test]
+    fn remove_thinking_without_open_tag() {
+        // some providers send the open tag as part of the prompt, so only the close tag shows up
+        assert_eq!(remove_thinking("hmm</think>\nAnswer", THINK), "Answer");
+    }
+
+    #[test]
+    fn remove_thinking_unclosed_block() {
+        assert_eq!(
+            remove_thinking("Answer\n<think>still going", THINK),
+            "Answer"
+        );
+    }
+
+    #[test]
+    fn remove_thinking_leaves_plain_text() {
+        assert_eq!(remove_thinking("  Answer\n", THINK), "  Answer\n");
+    }
+
+    #[test]
+    fn read_limited_counts_bytes() {
+        let args = args(&["-c", "10"]);
+        assert_eq!(read_limited("ééééé".as_bytes(), &args).unwrap(), "ééééé");
+        let error = read_limited("éééééé".as_bytes(), &args).unwrap_err();
+        assert!(error.to_string().contains("12 bytes given"));
+    }
+
+    #[test]
+    fn read_limited_agrees_with_check_limits() {
+        let args = args(&["-c", "10"]);
+        let fits = vec![Message::new(
+            "user",
+            read_limited("ééééé".as_bytes(), &args).unwrap(),
+        )];
+        assert_eq!(check_limits(&args, None, &fits).unwrap(), 10);
+        let over = vec![Message::new("user", "éééééé".to_string())];
+        assert!(check_limits(&args, None, &over).is_err());
+    }
+
+    #[test]
+    fn read_limited_ignores_surrounding_whitespace() {
+        let args = args(&["-c", "3"]);
+        assert!(read_limited(" \n abc \n\n".as_bytes(), &args).is_ok());
+        assert!(read_limited("a  b".as_bytes(), &args).is_err());
+    }
+
+    #[test]
+    fn read_limited_counts_whitespace_with_no_trim() {
+        let args = args(&["-c", "3", "--no-trim"]);
+        assert!(read_limited(" abc".as_bytes(), &args).is_err());
+    }
+
+    #[test]
+    fn read_limited_stops_early() {
+        // an endless input has to be rejected rather than read to the end
+        let args = args(&["-c", "100"]);
+        assert!(read_limited(io::repeat(b'a'), &args).is_err());
+    }
+
+    #[test]
+    fn plain_names() {
+        assert!(is_plain_name("coder"));
+        assert!(is_plain_name("my.prompt"));
+        assert!(is_plain_name("..."));
+    }
+
+    #[test]
+    fn names_that_escape_are_not_plain() {
+        for name in ["", ".", "..", "../x", "a/b", "a/", "/etc", "a\\b", "..\\x"] {
+            assert!(!is_plain_name(name), "{name:?}");
         }
     }
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post("https://openrouter.ai/api/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
-        .await
-        .context("Failed to send API request")?;
+    #[test]
+    fn short_output_is_not_truncated() {
+        assert_eq!(truncate_output("abc".to_string(), 3).unwrap(), "abc");
+    }
 
-    // Check if the response status is successful
-    if !response.status().is_success() {
-        let status = response.status();
-        let response_text = response.text().await?;
-        return Err(anyhow::anyhow!(format!(
-            "API responeded with status {}; Response body was: {}",
-            status, response_text
-        )));
+    #[test]
+    fn truncated_output_fits_the_budget() {
+        let output = "a".repeat(100);
+        let truncated = truncate_output(output, 20).unwrap();
+        assert_eq!(truncated.len(), 20);
+        assert!(truncated.ends_with(TRUNCATED_MARKER));
+
+        let truncated = truncate_output("a".repeat(100), TRUNCATED_MARKER.len()).unwrap();
+        assert_eq!(truncated, TRUNCATED_MARKER);
     }
 
-    let response_text = response.text().await?;
-    let response_json: OpenRouterResponse = match serde_json::from_str(&response_text) {
-        Ok(json) => json,
-        Err(e) => {
-            return Err(e).context(format!(
-                "Failed to parse JSON of the API request response; Response body was: {}",
-                response_text
-            ));
+    #[test]
+    fn truncation_keeps_characters_whole() {
+        let budget = TRUNCATED_MARKER.len() + 3;
+        let truncated = truncate_output("é".repeat(20), budget).unwrap();
+        assert_eq!(truncated, format!("é{}", TRUNCATED_MARKER));
+    }
+
+    #[test]
+    fn budget_without_room_for_the_marker() {
+        assert!(truncate_output("a".repeat(100), TRUNCATED_MARKER.len() - 1).is_none());
+        assert!(truncate_output("a".repeat(100), 0).is_none());
+    }
+
+    fn definitions(names: &[&str]) -> Vec<ToolDefinition> {
+        names
+            .iter()
+            .map(|name| {
+                serde_json::from_value(serde_json::json!({
+                    "type": "function",
+                    "function": {"name": name},
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn names(definitions: &[ToolDefinition]) -> Vec<&str> {
+        definitions
+            .iter()
+            .map(|definition| definition.function.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn tools_are_sorted_by_name() {
+        let mut definitions = definitions(&["c", "a", "b"]);
+        order_tools(&mut definitions, &[]).unwrap();
+        assert_eq!(names(&definitions), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ordered_tools_come_first() {
+        let mut definitions = definitions(&["a", "b", "c", "d"]);
+        order_tools(&mut definitions, &["d".to_string(), "b".to_string()]).unwrap();
+        assert_eq!(names(&definitions), ["d", "b", "a", "c"]);
+    }
+
+    #[test]
+    fn ordering_unknown_tools_fails() {
+        let mut definitions = definitions(&["a"]);
+        assert!(order_tools(&mut definitions, &["x".to_string()]).is_err());
+    }
+
+    fn roles_and_contents(messages: &[Message]) -> Vec<(&str, Option<&str>)> {
+        messages
+            .iter()
+            .map(|message| (message.role.as_str(), message.content.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn history_formats_agree() {
+        let json = r#"[
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Line one\nline two"},
+            {"role": "assistant", "content": null}
+        ]"#;
+        let yaml = "
+- role: system
+  content: Be brief.
+- role: user
+  content: |-
+    Line one
+    line two
+- role: assistant
+";
+        let toml = r#"
+[[messages]]
+role = "system"
+content = "Be brief."
+
+[[messages]]
+role = "user"
+content = """
+Line one
+line two"""
+
+[[messages]]
+role = "assistant"
+"#;
+        let expected = [
+            ("system", Some("Be brief.")),
+            ("user", Some("Line one\nline two")),
+            ("assistant", None),
+        ];
+        for (input, format) in [
+            (json, InputFormat::Json),
+            (yaml, InputFormat::Yaml),
+            (toml, InputFormat::Toml),
+        ] {
+            let messages = parse_history(input, format, "in test").unwrap();
+            assert_eq!(roles_and_contents(&messages), expected);
         }
-    };
+    }
+
+    #[test]
+    fn yaml_history_with_tool_calls() {
+        let yaml = r#"
+- role: assistant
+  tool_calls:
+    - id: a
+      type: function
+      function: {name: echo, arguments: '{"x": 1}'}
+- role: tool
+  tool_call_id: a
+  content: "1"
+"#;
+        let messages = parse_history(yaml, InputFormat::Yaml, "in test").unwrap();
+        let tool_calls = messages[0].tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.arguments, "{\"x\": 1}");
+        assert_eq!(messages[1].tool_call_id.as_deref(), Some("a"));
+    }
 
-    let first_choice = response_json
-        .choices
-        .first()
-        .context("No response from LLM API")?;
+    #[test]
+    fn toml_history_needs_messages_tables() {
+        let error = parse_history("role = \"user\"", InputFormat::Toml, "in a.toml")
+            .err()
+            .unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("in a.toml (expected a [[messages]]")
+        );
+        let extra = "model = \"x\"\n[[messages]]\nrole = \"user\"\ncontent = \"Hi\"";
+        assert!(parse_history(extra, InputFormat::Toml, "in a.toml").is_err());
+    }
 
-    print!("{}", first_choice.message.content);
-    Ok(())
+    #[test]
+    fn history_format_from_extension() {
+        assert!(InputFormat::from_path(Path::new("a.yml")) == Some(InputFormat::Yaml));
+        assert!(InputFormat::from_path(Path::new("a.yaml")) == Some(InputFormat::Yaml));
+        assert!(InputFormat::from_path(Path::new("a.toml")) == Some(InputFormat::Toml));
+        assert!(InputFormat::from_path(Path::new("a.json")) == Some(InputFormat::Json));
+        assert!(InputFormat::from_path(Path::new("a.txt")).is_none());
+        assert!(InputFormat::from_path(Path::new("history")).is_none());
+    }
+
+    fn with_headers(headers: &[(&str, &str)]) -> reqwest::Response {
+        let mut response = http::Response::builder().status(429);
+        for &(name, value) in headers {
+            response = response.header(name, value);
+        }
+        response.body("").unwrap().into()
+    }
+
+    fn now() -> Duration {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        let response = with_headers(&[("retry-after", " 7 ")]);
+        assert_eq!(get_retry_after(&response), Some(Duration::from_secs(7)));
+        // the date form is not supported
+        let response = with_headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert_eq!(get_retry_after(&response), None);
+    }
+
+    #[test]
+    fn retry_after_wins_over_reset() {
+        let response = with_headers(&[("retry-after", "3"), ("x-ratelimit-reset", "60")]);
+        assert_eq!(
+            get_rate_limit_reset(&response),
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn reset_as_a_delay() {
+        let response = with_headers(&[("x-ratelimit-reset", "60")]);
+        assert_eq!(
+            get_rate_limit_reset(&response),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn reset_as_a_timestamp() {
+        let in_a_minute = now() + Duration::from_secs(60);
+        for value in [in_a_minute.as_millis() as u64, in_a_minute.as_secs()] {
+            let response = with_headers(&[("x-ratelimit-reset", &value.to_string())]);
+            let wait = get_rate_limit_reset(&response).unwrap();
+            assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        }
+
+        // a reset in the past means there is nothing to wait for
+        let past = (now() - Duration::from_secs(60)).as_millis() as u64;
+        let response = with_headers(&[("x-ratelimit-reset", &past.to_string())]);
+        assert_eq!(get_rate_limit_reset(&response), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn no_reset_headers() {
+        assert_eq!(get_rate_limit_reset(&with_headers(&[])), None);
+        let response = with_headers(&[("x-ratelimit-reset", "soon")]);
+        assert_eq!(get_rate_limit_reset(&response), None);
+    }
+
+    fn status_error(status: u16, body: &str) -> StatusError {
+        StatusError {
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn missing_models_are_unavailable() {
+        let body = r#"{"error":{"message":"No endpoints found for foo/bar.","code":404}}"#;
+        assert!(status_error(404, body).is_model_unavailable());
+        let body = r#"{"error":{"message":"foo/bar is not a valid model ID","code":400}}"#;
+        assert!(status_error(400, body).is_model_unavailable());
+        let body =
+            r#"{"error":{"message":"The model `x` does not exist","code":"model_not_found"}}"#;
+        assert!(status_error(404, body).is_model_unavailable());
+    }
+
+    #[test]
+    fn models_without_capacity_are_unavailable() {
+        for status in [429, 502, 503, 529] {
+            assert!(status_error(status, "").is_model_unavailable(), "{status}");
+        }
+    }
+
+    #[test]
+    fn other_errors_about_the_model_are_not() {
+        for body in [
+            "This model does not support tools",
+            "max_tokens exceeds the model limit",
+        ] {
+            assert!(!status_error(400, body).is_model_unavailable(), "{body}");
+        }
+        assert!(!status_error(404, "Not Found").is_model_unavailable());
+        assert!(!status_error(500, "model crashed").is_model_unavailable());
+    }
+
+    fn assistant_with_calls(calls: usize) -> Message {
+        let tool_calls = (0..calls)
+            .map(|index| ToolCall {
+                id: index.to_string(),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: "echo".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            })
+            .collect();
+        Message {
+            tool_calls: Some(tool_calls),
+            ..Message::new("assistant", String::new())
+        }
+    }
+
+    #[test]
+    fn interrupted_continuations_are_not_saved() {
+        let messages = vec![
+            Message::new("user", "earlier".to_string()),
+            Message::new("assistant", "answer".to_string()),
+            Message::new("user", "Write a story".to_string()),
+            Message::new("assistant", "Once".to_string()),
+            Message::new("user", CONTINUE_PROMPT.to_string()),
+            Message::new("assistant", " upon".to_string()),
+            Message::new("user", CONTINUE_PROMPT.to_string()),
+        ];
+        assert_eq!(get_interrupted_end(&args(&[]), &messages, 2), 3);
+        // the earlier history is never cut
+        assert_eq!(get_interrupted_end(&args(&[]), &messages, 5), 5);
+    }
+
+    #[test]
+    fn interrupted_prefill_is_not_saved() {
+        let messages = vec![
+            Message::new("user", "Hi".to_string()),
+            Message::new("assistant", "{".to_string()),
+        ];
+        assert_eq!(
+            get_interrupted_end(&args(&["--prefill", "{"]), &messages, 0),
+            1
+        );
+        assert_eq!(get_interrupted_end(&args(&[]), &messages, 0), 2);
+    }
+
+    #[test]
+    fn interrupted_tool_calls_are_not_saved() {
+        let mut messages = vec![
+            Message::new("user", "Hi".to_string()),
+            assistant_with_calls(2),
+        ];
+        messages.push(Message {
+            tool_call_id: Some("0".to_string()),
+            ..Message::new("tool", "result".to_string())
+        });
+        assert_eq!(get_interrupted_end(&args(&[]), &messages, 0), 1);
+
+        messages.push(Message {
+            tool_call_id: Some("1".to_string()),
+            ..Message::new("tool", "result".to_string())
+        });
+        assert_eq!(get_interrupted_end(&args(&[]), &messages, 0), 4);
+    }
 }
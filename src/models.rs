@@ -0,0 +1,242 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::{OPENROUTER_API_URL, get_config_dir};
+
+/// How long the cached list of models is used before it is fetched again.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+/// A model, as listed by the OpenRouter `/models` endpoint.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub context_length: Option<u64>,
+    #[serde(default)]
+    pub pricing: Option<Pricing>,
+}
+
+/// Prices in dollars per token. OpenRouter sends them as strings, to avoid rounding errors.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Pricing {
+    pub prompt: String,
+    pub completion: String,
+}
+
+impl Pricing {
+    /// Returns the cost of the given number of prompt and completion tokens, or `None` if the
+    /// prices could not be parsed.
+    pub fn cost(&self, prompt_tokens: u64, completion_tokens: u64) -> Option<f64> {
+        let prompt = self.prompt.parse::<f64>().ok()?;
+        let completion = self.completion.parse::<f64>().ok()?;
+        Some(prompt * prompt_tokens as f64 + completion * completion_tokens as f64)
+    }
+}
+
+fn get_cache_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("cache").join("models.json"))
+}
+
+/// Reads the cached list of models, if there is one and it is recent enough (or `stale_ok` is set).
+fn read_cache(stale_ok: bool) -> Option<Vec<ModelInfo>> {
+    let path = get_cache_path().ok()?;
+
+    let age = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+    if age > CACHE_MAX_AGE && !stale_ok {
+        return None;
+    }
+
+    let content = fs::read_to_string(&path).ok()?;
+    let list: ModelList = serde_json::from_str(&content).ok()?;
+    Some(list.data)
+}
+
+fn write_cache(content: &str) -> Result<()> {
+    let path = get_cache_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {}", parent.display()))?;
+    }
+    fs::write(&path, content).with_context(|| format!("Could not write {}", path.display()))
+}
+
+async fn fetch_models(
+    client: &reqwest::Client,
+    api_key: Option<&str>,
+) -> Result<(Vec<ModelInfo>, String)> {
+    let mut request = client.get(format!("{}/models", OPENROUTER_API_URL));
+    if let Some(api_key) = api_key {
+        request = request.header("Authorization", format!("Bearer {}", api_key));
+    }
+    let response = request
+        .send()
+        .await
+        .context("Failed to send models request")?;
+
+    let status = response.status();
+    let response_text = response
+        .text()
+        .await
+        .context("Failed to read models response")?;
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "API responeded with status {}; Response body was: {}",
+            status,
+            response_text
+        ));
+    }
+
+    let list: ModelList = serde_json::from_str(&response_text)
+        .context("Failed to parse JSON of the models response")?;
+    Ok((list.data, response_text))
+}
+
+/// Errors out if `model` is not in the list of models, suggesting the most similar ones. Variant
+/// suffixes (e.g. ":nitro") are accepted on every listed model.
+pub fn check_model(models: &[ModelInfo], model: &str) -> Result<()> {
+    let base = model.split_once(':').map_or(model, |(base, _)| base);
+    if models
+        .iter()
+        .any(|info| info.id == model || info.id == base)
+    {
+        return Ok(());
+    }
+
+    let mut candidates: Vec<(usize, &str)> = models
+        .iter()
+        .map(|info| (levenshtein(model, &info.id), info.id.as_str()))
+        .filter(|&(distance, _)| distance <= model.len() / 3 + 1)
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    if candidates.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Unknown model {} (use --no-validate-model if it exists anyway)",
+            model
+        ));
+    }
+    let suggestions: Vec<&str> = candidates.iter().take(3).map(|&(_, id)| id).collect();
+    Err(anyhow::anyhow!(
+        "Unknown model {}; did you mean {}? (use --no-validate-model if it exists anyway)",
+        model,
+        suggestions.join(", ")
+    ))
+}
+
+/// Number of single-character insertions, deletions and substitutions that turn `a` into `b`.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns the list of available models. It is cached in `~/.config/rapidllm/cache/models.json`
+/// for a day, since it rarely changes and fetching it every time would slow every run down.
+pub async fn get_models(client: &reqwest::Client, api_key: Option<&str>) -> Result<Vec<ModelInfo>> {
+    if let Some(models) = read_cache(false) {
+        return Ok(models);
+    }
+
+    match fetch_models(client, api_key).await {
+        Ok((models, content)) => {
+            // failing to cache is no reason to fail the whole run
+            write_cache(&content).ok();
+            Ok(models)
+        }
+        // an outdated list is still better than no list at all
+        Err(e) => read_cache(true).ok_or(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(ids: &[&str]) -> Vec<ModelInfo> {
+        ids.iter()
+            .map(|id| ModelInfo {
+                id: id.to_string(),
+                context_length: None,
+                pricing: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn levenshtein_distances() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("gpt-4o", "gpt-4o"), 0);
+        assert_eq!(levenshtein("añb", "ab"), 1);
+    }
+
+    #[test]
+    fn known_models_pass() {
+        let models = models(&["openai/gpt-4o", "openai/gpt-4o:free"]);
+        assert!(check_model(&models, "openai/gpt-4o").is_ok());
+        assert!(check_model(&models, "openai/gpt-4o:free").is_ok());
+        assert!(check_model(&models, "openai/gpt-4o:nitro").is_ok());
+    }
+
+    #[test]
+    fn typos_get_suggestions() {
+        let models = models(&["openai/gpt-4o", "openai/gpt-4o-mini", "google/gemini-pro"]);
+        let error = check_model(&models, "openai/gpt4o")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("did you mean openai/gpt-4o?"));
+        assert!(!error.contains("gemini"));
+        assert!(check_model(&models, "openai/gpt4o:nitro").is_err());
+    }
+
+    #[test]
+    fn unrelated_models_get_no_suggestions() {
+        let models = models(&["openai/gpt-4o"]);
+        let error = check_model(&models, "meta/llama").unwrap_err().to_string();
+        assert!(error.starts_with("Unknown model meta/llama (use"));
+    }
+}
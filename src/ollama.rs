@@ -0,0 +1,370 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+
+use crate::tools::ToolDefinition;
+use crate::{
+    Choice, FunctionCall, Message, OpenRouterRequest, OpenRouterResponse, ResponseFormat, ToolCall,
+    Usage,
+};
+
+/// Base URL of a local Ollama server.
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+// Ollama's native chat API (/api/chat) is close to the OpenAI one, but differs in the details:
+// sampling parameters go into "options", tool call arguments are JSON objects rather than
+// strings, tool calls have no ids, and the token usage is reported as eval counts.
+
+#[derive(serde::Serialize)]
+pub struct OllamaRequest<'a> {
+    model: &'a str,
+    messages: Vec<OllamaMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<&'a Vec<ToolDefinition>>,
+    stream: bool, // Ollama streams by default, so this has to be sent explicitly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>, // "json", or a JSON schema
+    #[serde(skip_serializing_if = "Options::is_empty")]
+    options: Options,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaMessage {
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>, // base64, without the data URL prefix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    arguments: serde_json::Value,
+}
+
+#[derive(serde::Serialize)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>, // same as max_tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+}
+
+impl Options {
+    fn is_empty(&self) -> bool {
+        self.temperature.is_none()
+            && self.top_p.is_none()
+            && self.top_k.is_none()
+            && self.min_p.is_none()
+            && self.num_predict.is_none()
+            && self.presence_penalty.is_none()
+            && self.frequency_penalty.is_none()
+            && self.stop.is_empty()
+            && self.seed.is_none()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct OllamaResponse {
+    model: Option<String>,
+    message: OllamaMessage,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+    done_reason: Option<String>, // "stop" or "length", like finish_reason
+}
+
+/// Translates a request into the shape of Ollama's chat API. Streaming is not supported, the
+/// response is always received at once.
+pub fn to_request(request_body: &OpenRouterRequest) -> OllamaRequest<'_> {
+    let messages = request_body
+        .messages
+        .iter()
+        .map(|message| OllamaMessage {
+            role: message.role.clone(),
+            content: message.content.clone().unwrap_or_default(),
+            // Ollama can't fetch images itself, so only the embedded ones are sent
+            images: message
+                .images
+                .iter()
+                .filter_map(|url| url.split_once(";base64,").map(|(_, data)| data.to_string()))
+                .collect(),
+            tool_calls: message.tool_calls.as_ref().map(|tool_calls| {
+                tool_calls
+                    .iter()
+                    .map(|tool_call| OllamaToolCall {
+                        function: OllamaFunctionCall {
+                            name: tool_call.function.name.clone(),
+                            arguments: tool_call.function.arguments_object(),
+                        },
+                    })
+                    .collect()
+            }),
+        })
+        .collect();
+
+    OllamaRequest {
+        model: &request_body.model,
+        messages,
+        tools: request_body.tools.as_ref(),
+        stream: false,
+        format: request_body
+            .response_format
+            .as_ref()
+            .map(|format| match format {
+                ResponseFormat::JsonObject => serde_json::Value::from("json"),
+                ResponseFormat::JsonSchema { json_schema } => json_schema.schema.clone(),
+            }),
+        options: Options {
+            temperature: request_body.temperature,
+            top_p: request_body.top_p,
+            top_k: request_body.top_k,
+            min_p: request_body.min_p,
+            num_predict: request_body.max_tokens,
+            presence_penalty: request_body.presence_penalty,
+            frequency_penalty: request_body.frequency_penalty,
+            stop: request_body.stop.clone(),
+            seed: request_body.seed,
+        },
+    }
+}
+
+/// Parses a response of Ollama's chat API into the common response shape.
+pub fn parse_response(response_text: &str) -> Result<OpenRouterResponse> {
+    let response: OllamaResponse = serde_json::from_str(response_text).with_context(|| {
+        format!(
+            "Failed to parse JSON of the API request response; Response body was: {}",
+            response_text
+        )
+    })?;
+
+    // Ollama doesn't identify tool calls, so ids are made up to pair them with their results
+    let tool_calls = response.message.tool_calls.map(|tool_calls| {
+        tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, tool_call)| ToolCall {
+                id: format!("call_{}", index),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: tool_call.function.name,
+                    arguments: tool_call.function.arguments.to_string(),
+                },
+            })
+            .collect()
+    });
+
+    let message = Message {
+        role: response.message.role,
+        content: Some(response.message.content),
+        images: Vec::new(),
+        tool_calls,
+        tool_call_id: None,
+        reasoning: None,
+    };
+
+    let usage = match (response.prompt_eval_count, response.eval_count) {
+        (Some(prompt_tokens), Some(completion_tokens)) => Some(Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }),
+        _ => None,
+    };
+
+    Ok(OpenRouterResponse {
+        id: None,
+        model: response.model,
+        choices: vec![Choice {
+            message,
+            finish_reason: response.done_reason,
+        }],
+        usage,
+        error: None,
+        seed: None,
+        system_fingerprint: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(request_body: OpenRouterRequest) -> serde_json::Value {
+        serde_json::to_value(to_request(&request_body)).unwrap()
+    }
+
+    #[test]
+    fn sampling_parameters_go_into_options() {
+        let request = translate(OpenRouterRequest {
+            model: "llama3.1".to_string(),
+            messages: vec![Message::new("user", "Hi".to_string())],
+            max_tokens: Some(100),
+            temperature: Some(0.5),
+            stop: vec!["END".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            request,
+            serde_json::json!({
+                "model": "llama3.1",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "stream": false,
+                "options": {"temperature": 0.5, "num_predict": 100, "stop": ["END"]},
+            })
+        );
+    }
+
+    #[test]
+    fn no_options_are_left_out() {
+        let request = translate(OpenRouterRequest {
+            model: "llama3.1".to_string(),
+            ..Default::default()
+        });
+        assert!(request.get("options").is_none());
+        assert_eq!(request["stream"], false);
+    }
+
+    #[test]
+    fn response_formats_become_format() {
+        let request = translate(OpenRouterRequest {
+            response_format: Some(ResponseFormat::JsonObject),
+            ..Default::default()
+        });
+        assert_eq!(request["format"], "json");
+
+        let schema = serde_json::json!({"type": "object"});
+        let request = translate(OpenRouterRequest {
+            response_format: Some(ResponseFormat::JsonSchema {
+                json_schema: crate::JsonSchema {
+                    name: "answer".to_string(),
+                    strict: true,
+                    schema: schema.clone(),
+                },
+            }),
+            ..Default::default()
+        });
+        assert_eq!(request["format"], schema);
+    }
+
+    #[test]
+    fn only_embedded_images_are_sent() {
+        let message = Message {
+            images: vec![
+                "data:image/png;base64,AAAA".to_string(),
+                "https://example.com/a.png".to_string(),
+            ],
+            ..Message::new("user", "Look".to_string())
+        };
+        let request = translate(OpenRouterRequest {
+            messages: vec![message],
+            ..Default::default()
+        });
+        assert_eq!(
+            request["messages"][0]["images"],
+            serde_json::json!(["AAAA"])
+        );
+    }
+
+    #[test]
+    fn tool_call_arguments_are_objects() {
+        let message = Message {
+            tool_calls: Some(vec![ToolCall {
+                id: "call_0".to_string(),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: "echo".to_string(),
+                    arguments: "{\"x\":1}".to_string(),
+                },
+            }]),
+            ..Message::new("assistant", String::new())
+        };
+        let request = translate(OpenRouterRequest {
+            messages: vec![message],
+            ..Default::default()
+        });
+        assert_eq!(
+            request["messages"][0]["tool_calls"],
+            serde_json::json!([{"function": {"name": "echo", "arguments": {"x": 1}}}])
+        );
+    }
+
+    #[test]
+    fn responses_are_translated() {
+        let response = parse_response(
+            r#"{
+                "model": "llama3.1",
+                "message": {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [
+                        {"function": {"name": "a", "arguments": {"x": 1}}},
+                        {"function": {"name": "b", "arguments": {}}}
+                    ]
+                },
+                "done_reason": "stop",
+                "prompt_eval_count": 10,
+                "eval_count": 5
+            }"#,
+        )
+        .unwrap();
+
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("stop"));
+        let tool_calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "call_0");
+        assert_eq!(tool_calls[1].id, "call_1");
+        assert_eq!(tool_calls[0].function.arguments, "{\"x\":1}");
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    #[test]
+    fn usage_needs_both_counts() {
+        let response = parse_response(
+            r#"{"message": {"role": "assistant", "content": "Hi"}, "eval_count": 5}"#,
+        )
+        .unwrap();
+        assert!(response.usage.is_none());
+        assert_eq!(response.choices[0].message.content.as_deref(), Some("Hi"));
+    }
+}
@@ -0,0 +1,74 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Runs the --postprocess command in the shell, with the answer on its stdin, and returns its
+/// stdout.
+pub fn postprocess(command: &str, content: &str) -> Result<String> {
+    let mut child = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .with_context(|| format!("Could not run {}", command))?;
+
+    // written from a separate thread, like the input of tools: a command that prints a lot before
+    // it has read all of its input would otherwise deadlock with us
+    let writer = child.stdin.take().map(|mut stdin| {
+        let content = content.to_string();
+        thread::spawn(move || stdin.write_all(content.as_bytes()))
+    });
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Could not run {}", command))?;
+    if let Some(writer) = writer {
+        // the command is free to exit without reading the answer, so a broken pipe is no error
+        match writer.join() {
+            Ok(Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(e).context("Could not write the answer to the stdin of --postprocess");
+            }
+            _ => {}
+        }
+    }
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} failed with {}; stderr was: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).context("Output of --postprocess is not valid UTF-8")
+}
@@ -0,0 +1,115 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::{Message, get_config_dir, is_plain_name};
+
+/// Returns the path of the session file, `~/.config/rapidllm/sessions/<name>.json`.
+pub fn get_session_path(name: &str) -> Result<PathBuf> {
+    // same reasoning as with prompt names: without path separators, the name cannot escape the
+    // sessions directory
+    if !is_plain_name(name) {
+        return Err(anyhow::anyhow!("Invalid session name: {}", name));
+    }
+
+    let mut path = get_config_dir()?;
+    path.push("sessions");
+    path.push(format!("{}.json", name));
+    Ok(path)
+}
+
+/// Returns the path of a checkpoint, `~/.config/rapidllm/checkpoints/<name>.json`.
+pub fn get_checkpoint_path(name: &str) -> Result<PathBuf> {
+    if !is_plain_name(name) {
+        return Err(anyhow::anyhow!("Invalid checkpoint name: {}", name));
+    }
+
+    let mut path = get_config_dir()?;
+    path.push("checkpoints");
+    path.push(format!("{}.json", name));
+    Ok(path)
+}
+
+/// Loads the messages of a session. A session that doesn't exist yet has no messages.
+pub fn load_session(name: &str) -> Result<Vec<Message>> {
+    let path = get_session_path(name)?;
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("Could not open file {}", path.display())),
+    };
+
+    serde_json::from_str(&content).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Loads the messages of a checkpoint. Unlike a session, a checkpoint has to exist: starting from
+/// one that doesn't is a mistake.
+pub fn load_checkpoint(name: &str) -> Result<Vec<Message>> {
+    let path = get_checkpoint_path(name)?;
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!("Checkpoint {} does not exist", name));
+        }
+        Err(e) => return Err(e).context(format!("Could not open file {}", path.display())),
+    };
+
+    serde_json::from_str(&content).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Deletes the file of a session.
+pub fn clear_session(name: &str) -> Result<()> {
+    let path = get_session_path(name)?;
+
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err(anyhow::anyhow!("Session {} does not exist", name))
+        }
+        Err(e) => Err(e).context(format!("Could not delete {}", path.display())),
+    }
+}
+
+/// Writes the messages of a session, replacing whatever was stored before.
+pub fn save_session(name: &str, messages: &[Message]) -> Result<()> {
+    write_messages(&get_session_path(name)?, messages)
+}
+
+/// Writes the messages of a checkpoint, replacing whatever was stored before.
+pub fn save_checkpoint(name: &str, messages: &[Message]) -> Result<()> {
+    write_messages(&get_checkpoint_path(name)?, messages)
+}
+
+fn write_messages(path: &Path, messages: &[Message]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {}", parent.display()))?;
+    }
+
+    let content = serde_json::to_string_pretty(messages).context("Could not serialize messages")?;
+    fs::write(path, content).with_context(|| format!("Could not write {}", path.display()))
+}
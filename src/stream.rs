@@ -0,0 +1,320 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::io::{self, Write};
+
+use crate::{ApiError, Choice, FunctionCall, Message, OpenRouterResponse, ToolCall, Usage};
+
+#[derive(serde::Deserialize)]
+struct StreamChunk {
+    id: Option<String>,
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,    // usually only sent in the last chunk
+    error: Option<ApiError>, // errors after the stream has started can't change the status
+    system_fingerprint: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+    finish_reason: Option<String>, // only sent in the last chunk of the choice
+}
+
+#[derive(serde::Deserialize)]
+struct Delta {
+    content: Option<String>,
+    reasoning: Option<String>, // never printed, it is not part of the answer
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+// tool calls are streamed in pieces: the first delta of a call carries its id and name, and the
+// following ones carry fragments of the arguments. The index tells which call a fragment belongs to.
+#[derive(serde::Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallDelta>,
+}
+
+#[derive(serde::Deserialize)]
+struct FunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+// what the chunks of a stream tell about the response as a whole; each field is taken from the
+// last chunk that has it
+#[derive(Default)]
+struct Metadata {
+    id: Option<String>,
+    model: Option<String>,
+    usage: Option<Usage>,
+    system_fingerprint: Option<String>,
+    finish_reason: Option<String>,
+}
+
+/// Consumes a Server-Sent Events response of a streamed completion. The content is printed to
+/// stdout as it arrives (if `print` is set), and the fully reassembled response is returned once
+/// the stream ends.
+pub async fn read_response(response: reqwest::Response, print: bool) -> Result<OpenRouterResponse> {
+    let mut stream = response.bytes_stream();
+    let mut buffer = Vec::<u8>::new();
+
+    let mut content = String::new();
+    let mut reasoning = String::new();
+    let mut tool_calls = Vec::<ToolCall>::new();
+    let mut metadata = Metadata::default();
+
+    'stream: while let Some(bytes) = stream.next().await {
+        let bytes = bytes.context("Failed to read API response stream")?;
+        buffer.extend_from_slice(&bytes);
+
+        // only complete lines are processed, since a chunk may end in the middle of an event (or
+        // even in the middle of a multibyte character)
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8(line).context("API response stream is not valid UTF-8")?;
+            let line = line.trim();
+
+            // everything that isn't data is either an empty line separating events, or a comment
+            // (OpenRouter sends those as keep-alives)
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+
+            // some providers simply close the connection instead of sending [DONE]
+            if data == "[DONE]" {
+                break 'stream;
+            }
+
+            let chunk: StreamChunk = serde_json::from_str(data).with_context(|| {
+                format!(
+                    "Failed to parse JSON of the API stream event; Event was: {}",
+                    data
+                )
+            })?;
+
+            if let Some(error) = chunk.error {
+                return Err(error.into_error());
+            }
+            if chunk.id.is_some() {
+                metadata.id = chunk.id;
+            }
+            if chunk.model.is_some() {
+                metadata.model = chunk.model;
+            }
+            if chunk.usage.is_some() {
+                metadata.usage = chunk.usage;
+            }
+            if chunk.system_fingerprint.is_some() {
+                metadata.system_fingerprint = chunk.system_fingerprint;
+            }
+
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                continue;
+            };
+            if choice.finish_reason.is_some() {
+                metadata.finish_reason = choice.finish_reason;
+            }
+
+            if let Some(delta_content) = choice.delta.content {
+                if print {
+                    print!("{}", delta_content);
+                    io::stdout().flush().context("Could not write to stdout")?;
+                }
+                content.push_str(&delta_content);
+            }
+            if let Some(delta_reasoning) = choice.delta.reasoning {
+                reasoning.push_str(&delta_reasoning);
+            }
+
+            for delta in choice.delta.tool_calls.unwrap_or_default() {
+                if delta.index >= tool_calls.len() {
+                    tool_calls.resize_with(delta.index + 1, || ToolCall {
+                        id: String::new(),
+                        call_type: "function".to_string(),
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    });
+                }
+                let tool_call = &mut tool_calls[delta.index];
+
+                if let Some(id) = delta.id {
+                    tool_call.id = id;
+                }
+                if let Some(function) = delta.function {
+                    if let Some(name) = function.name {
+                        tool_call.function.name.push_str(&name);
+                    }
+                    if let Some(arguments) = function.arguments {
+                        tool_call.function.arguments.push_str(&arguments);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(assemble(content, reasoning, tool_calls, metadata))
+}
+
+fn assemble(
+    content: String,
+    reasoning: String,
+    tool_calls: Vec<ToolCall>,
+    metadata: Metadata,
+) -> OpenRouterResponse {
+    let message = Message {
+        role: "assistant".to_string(),
+        content: if content.is_empty() && !tool_calls.is_empty() {
+            None
+        } else {
+            Some(content)
+        },
+        images: Vec::new(),
+        tool_calls: if tool_calls.is_empty() {
+            None
+        } else {
+            Some(tool_calls)
+        },
+        tool_call_id: None,
+        reasoning: if reasoning.is_empty() {
+            None
+        } else {
+            Some(reasoning)
+        },
+    };
+
+    OpenRouterResponse {
+        id: metadata.id,
+        model: metadata.model,
+        choices: vec![Choice {
+            message,
+            finish_reason: metadata.finish_reason,
+        }],
+        usage: metadata.usage,
+        error: None,
+        seed: None,
+        system_fingerprint: metadata.system_fingerprint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a stream that arrives in the given pieces.
+    async fn read(pieces: &[&'static [u8]]) -> Result<OpenRouterResponse> {
+        let pieces: Vec<Result<&'static [u8], io::Error>> =
+            pieces.iter().map(|&piece| Ok(piece)).collect();
+        let body = reqwest::Body::wrap_stream(futures_util::stream::iter(pieces));
+        read_response(http::Response::new(body).into(), false).await
+    }
+
+    #[tokio::test]
+    async fn content_is_reassembled() {
+        let response = read(&[
+            b": OPENROUTER PROCESSING\n\n",
+            b"data: {\"id\":\"gen-1\",\"model\":\"m\",\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n",
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"lo\",\"reasoning\":\"hmm\"},\"finish_reason\":\"stop\"}]}\n\n",
+            b"data: {\"choices\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2,\"total_tokens\":5}}\n\n",
+            b"data: [DONE]\n\n",
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(response.id.as_deref(), Some("gen-1"));
+        assert_eq!(response.model.as_deref(), Some("m"));
+        assert_eq!(response.usage.unwrap().total_tokens, 5);
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.content.as_deref(), Some("Hello"));
+        assert_eq!(choice.message.reasoning.as_deref(), Some("hmm"));
+        assert_eq!(choice.finish_reason.as_deref(), Some("stop"));
+        assert!(choice.message.tool_calls.is_none());
+    }
+
+    #[tokio::test]
+    async fn events_split_anywhere_are_reassembled() {
+        // the pieces split an event, a "\r\n", and the two bytes of the "é"
+        let response = read(&[
+            b"data: {\"choices\":[{\"delta\":{\"con",
+            b"tent\":\"caf\xc3",
+            b"\xa9\"}}]}\r",
+            b"\n\r\ndata: [DONE]\r\n\r\n",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.choices[0].message.content.as_deref(), Some("café"));
+    }
+
+    #[tokio::test]
+    async fn tool_call_fragments_are_reassembled() {
+        let response = read(&[
+            b"data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"a\",\"function\":{\"name\":\"echo\",\"arguments\":\"\"}}]}}]}\n\n",
+            b"data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":1,\"id\":\"b\",\"function\":{\"name\":\"cat\",\"arguments\":\"{}\"}}]}}]}\n\n",
+            b"data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"x\\\":\"}}]}}]}\n\n",
+            b"data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"1}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n",
+        ])
+        .await
+        .unwrap();
+
+        let message = &response.choices[0].message;
+        assert!(message.content.is_none());
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "a");
+        assert_eq!(tool_calls[0].function.name, "echo");
+        assert_eq!(tool_calls[0].function.arguments, "{\"x\":1}");
+        assert_eq!(tool_calls[1].id, "b");
+        assert_eq!(tool_calls[1].function.arguments, "{}");
+    }
+
+    #[tokio::test]
+    async fn stream_may_end_without_done() {
+        let response = read(&[b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n"])
+            .await
+            .unwrap();
+        assert_eq!(response.choices[0].message.content.as_deref(), Some("Hi"));
+    }
+
+    #[tokio::test]
+    async fn errors_in_the_stream_are_returned() {
+        let response = read(&[
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            b"data: {\"error\":{\"code\":502,\"message\":\"Provider disconnected\"}}\n\n",
+        ])
+        .await;
+        let error = format!("{:#}", response.err().unwrap());
+        assert!(error.contains("Provider disconnected"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn empty_answer_keeps_its_content() {
+        let response = read(&[b"data: [DONE]\n\n"]).await.unwrap();
+        assert_eq!(response.choices[0].message.content.as_deref(), Some(""));
+    }
+}
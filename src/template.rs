@@ -0,0 +1,110 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Replaces the `{{name}}` placeholders in a prompt file with the values given with --var.
+/// Whitespace around the name is allowed (`{{ name }}`). Placeholders without a value are an
+/// error that names all of them, rather than something the model gets to see.
+pub fn fill(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut filled = String::with_capacity(template.len());
+    let mut missing = Vec::<&str>::new();
+
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + end].trim();
+
+        filled.push_str(&rest[..start]);
+        if !is_variable_name(name) {
+            // not a placeholder (e.g. a code example in the prompt), so it stays as it is
+            filled.push_str(&rest[start..start + 2 + end + 2]);
+        } else if let Some(value) = vars.get(name) {
+            filled.push_str(value);
+        } else if !missing.contains(&name) {
+            missing.push(name);
+        }
+        rest = &rest[start + 2 + end + 2..];
+    }
+    filled.push_str(rest);
+
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Missing values for template variables: {} (set them with --var <name>=<value>)",
+            missing.join(", ")
+        ));
+    }
+    Ok(filled)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let vars = vars(&[("lang", "Rust"), ("name", "Ann")]);
+        assert_eq!(
+            fill("{{lang}} for {{ name }}, in {{lang}}", &vars).unwrap(),
+            "Rust for Ann, in Rust"
+        );
+    }
+
+    #[test]
+    fn unclosed_placeholder_stays() {
+        let vars = vars(&[("lang", "Rust")]);
+        assert_eq!(
+            fill("{{lang}} and {{lang", &vars).unwrap(),
+            "Rust and {{lang"
+        );
+    }
+
+    #[test]
+    fn non_variables_stay() {
+        assert_eq!(
+            fill("{{}} {{ a b }} {{x.y}}", &HashMap::new()).unwrap(),
+            "{{}} {{ a b }} {{x.y}}"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_listed_once() {
+        let error = fill("{{a}} {{b}} {{a}}", &vars(&[("c", "")])).unwrap_err();
+        assert!(error.to_string().contains("variables: a, b ("));
+    }
+}
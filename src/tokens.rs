@@ -0,0 +1,59 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use tiktoken_rs::CoreBPE;
+
+use crate::Message;
+
+/// Returns the tokenizer of the given model, or cl100k_base when the model isn't known to
+/// tiktoken. The count is only exact for OpenAI models, but it is a much better estimate of the
+/// context usage than the character count for every other model too.
+pub fn get_tokenizer(model: &str) -> Result<CoreBPE> {
+    // OpenRouter model names are prefixed with the vendor, e.g. "openai/gpt-4o"
+    let name = model.rsplit('/').next().unwrap_or(model);
+
+    match tiktoken_rs::get_bpe_from_model(name) {
+        Ok(bpe) => Ok(bpe),
+        Err(_) => tiktoken_rs::cl100k_base().context("Could not load the cl100k_base tokenizer"),
+    }
+}
+
+/// Counts the tokens of all messages: their content, and the tool calls they carry.
+pub fn count_tokens(bpe: &CoreBPE, messages: &[Message]) -> usize {
+    let count = |text: &str| bpe.encode_with_special_tokens(text).len();
+
+    messages
+        .iter()
+        .map(|message| {
+            let content = message.content.as_deref().map_or(0, count);
+            let tool_calls: usize = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|tool_call| {
+                    count(&tool_call.function.name) + count(&tool_call.function.arguments)
+                })
+                .sum();
+            content + tool_calls
+        })
+        .sum()
+}
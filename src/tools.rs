@@ -0,0 +1,397 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::read_to_string;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ToolCall;
+
+/// A tool as described by `definition.json` in its tool directory. This is sent to the API as-is,
+/// inside the `tools` array of the request. Unknown fields are errors, since a misspelled one would
+/// otherwise be ignored without a word.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    pub tool_type: String, // always "function", for now
+    pub function: FunctionObject,
+
+    // the fields below configure how rlm runs the tool, and are not sent to the API
+    #[serde(default, skip_serializing)]
+    pub input: ToolInput,
+    #[serde(default, skip_serializing)]
+    pub output: ToolOutput,
+    #[serde(default, skip_serializing)]
+    pub env: HashMap<String, String>, // set for the exec, on top of the inherited environment
+    #[serde(default, skip_serializing)]
+    pub clear_env: bool, // don't pass rlm's own environment on to the exec
+    #[serde(default, skip_serializing)]
+    pub cwd: Option<PathBuf>, // working directory of the exec, relative to the tool directory
+    #[serde(default, skip_serializing)]
+    pub exec: Option<PathBuf>, // path of the exec relative to the tool directory, if not "exec"
+}
+
+/// How the call arguments are passed to the `exec` of a tool.
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolInput {
+    /// As the first command line argument.
+    #[default]
+    Argv,
+    /// Written to stdin. Unlike argv, this is not limited by ARG_MAX, and the arguments don't show
+    /// up in the process list.
+    Stdin,
+}
+
+/// How the stdout of the `exec` of a tool is turned into the content of the tool message.
+#[derive(serde::Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolOutput {
+    /// As-is. The output must be valid UTF-8.
+    #[default]
+    Text,
+    /// Base64-encoded, for tools that return binary data (e.g. images).
+    Base64,
+    /// A JSON array of messages, which are added to the conversation as they are. Output that
+    /// isn't such an array is used as-is, like `Text`.
+    Messages,
+}
+
+/// The exit status with which a tool says that its output is the final answer: it is printed instead
+/// of being sent back to the model, and the conversation ends.
+pub const FINAL_EXIT_CODE: i32 = 42;
+
+/// What a tool call produced.
+pub struct ToolResult {
+    pub output: String,
+    pub is_final: bool, // the tool exited with FINAL_EXIT_CODE
+}
+
+/// A tool that was loaded from its tool directory.
+#[derive(Clone)]
+pub struct Tool {
+    pub dir: PathBuf,
+    pub definition: ToolDefinition,
+    pub allowed: bool, // false if there is an allowlist, and the exec is not on it
+}
+
+impl Tool {
+    /// The absolute path of the executable of the tool.
+    pub fn exec_path(&self) -> Result<PathBuf> {
+        let exec = self.definition.exec.as_deref().unwrap_or(Path::new("exec"));
+        std::path::absolute(self.dir.join(exec)).context("Could not resolve the path of the tool")
+    }
+}
+
+/// Reads the allowlist of --allowed-tools: the absolute paths of the tool execs that may run, one
+/// per line. Empty lines and lines starting with `#` are ignored.
+pub fn load_allowlist(path: &Path) -> Result<Vec<PathBuf>> {
+    let content =
+        read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    let mut allowed = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let exec = PathBuf::from(line);
+        if !exec.is_absolute() {
+            return Err(anyhow::anyhow!(
+                "{} in {} is not an absolute path",
+                line,
+                path.display()
+            ));
+        }
+        // symlinks are resolved, so that a link to an allowed exec doesn't get around the list
+        allowed.push(fs::canonicalize(&exec).unwrap_or(exec));
+    }
+    Ok(allowed)
+}
+
+/// Whether the exec of `tool` is on the allowlist.
+pub fn is_allowed(tool: &Tool, allowlist: &[PathBuf]) -> Result<bool> {
+    let exec_path = tool.exec_path()?;
+    let exec_path = fs::canonicalize(&exec_path).unwrap_or(exec_path);
+    Ok(allowlist.contains(&exec_path))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FunctionObject {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>, // JSON schema of the arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>, // whether the arguments must follow the schema exactly
+}
+
+/// Reads `definition.json` from a tool directory.
+///
+/// A tool directory is expected to contain two files:
+/// 1. `definition.json` -- the tool definition, in the format the API expects.
+/// 2. `exec` -- an executable, which receives the call arguments (JSON) as its first argument (or
+///    on stdin, with `"input": "stdin"` in `definition.json`), and prints the result to stdout.
+pub fn load_tool_definition(tool_dir: &Path) -> Result<ToolDefinition> {
+    let path = tool_dir.join("definition.json");
+
+    let content =
+        read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    serde_json::from_str(&content).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Reads a tool manifest: a JSON file with an array of tool definitions, like the ones in
+/// `definition.json`, which bundles several tools in one file. Every definition has to give the
+/// path of its executable in `"exec"`; the directory of the manifest takes the place of the tool
+/// directory.
+pub fn load_tool_manifest(path: &Path) -> Result<Vec<(PathBuf, ToolDefinition)>> {
+    let content =
+        read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let definitions: Vec<ToolDefinition> = serde_json::from_str(&content)
+        .with_context(|| format!("Could not parse {}", path.display()))?;
+
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    for definition in &definitions {
+        let name = &definition.function.name;
+        let exec = definition
+            .exec
+            .as_ref()
+            .with_context(|| format!("Tool {} in {} has no \"exec\"", name, path.display()))?;
+        if !dir.join(exec).is_file() {
+            return Err(anyhow::anyhow!(
+                "Tool {} in {}: {} does not exist",
+                name,
+                path.display(),
+                dir.join(exec).display()
+            ));
+        }
+    }
+    Ok(definitions
+        .into_iter()
+        .map(|definition| (dir.clone(), definition))
+        .collect())
+}
+
+/// Loads every tool in the immediate subdirectories of `dir`, in the order of their names.
+/// Subdirectories without a valid `definition.json` are skipped.
+pub fn discover_tools(dir: &Path) -> Result<Vec<(PathBuf, ToolDefinition)>> {
+    let entries =
+        fs::read_dir(dir).with_context(|| format!("Could not read directory {}", dir.display()))?;
+
+    let mut tool_dirs = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Could not read directory {}", dir.display()))?
+            .path();
+        if path.is_dir() {
+            tool_dirs.push(path);
+        }
+    }
+    tool_dirs.sort();
+
+    let mut tools = Vec::new();
+    for tool_dir in tool_dirs {
+        match load_tool_definition(&tool_dir) {
+            Ok(definition) => tools.push((tool_dir, definition)),
+            Err(e) => debug!("Skipping {}: {:#}", tool_dir.display(), e),
+        }
+    }
+    Ok(tools)
+}
+
+/// Runs the `exec` of a tool for the given tool call, and returns its stdout. With a timeout, the
+/// tool is killed if it runs for longer than that.
+pub fn execute_tool(
+    tool: &Tool,
+    tool_call: &ToolCall,
+    timeout: Option<Duration>,
+) -> Result<ToolResult> {
+    // models do hallucinate malformed arguments; there is no point in bothering the tool with them
+    validate_arguments(tool, &tool_call.function.arguments).with_context(|| {
+        format!(
+            "Model called {} with invalid arguments: {}",
+            tool_call.function.name, tool_call.function.arguments
+        )
+    })?;
+
+    // whether a relative program path is resolved against the new working directory differs
+    // between platforms, so it is made absolute
+    let exec_path = tool.exec_path()?;
+
+    let mut command = Command::new(&exec_path);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    if tool.definition.clear_env {
+        command.env_clear();
+    }
+    command.envs(&tool.definition.env);
+    if let Some(cwd) = &tool.definition.cwd {
+        command.current_dir(tool.dir.join(cwd));
+    }
+    match tool.definition.input {
+        ToolInput::Argv => {
+            command
+                .arg(&tool_call.function.arguments)
+                .stdin(Stdio::null());
+        }
+        ToolInput::Stdin => {
+            command.stdin(Stdio::piped());
+        }
+    }
+
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Could not run {}", exec_path.display()))?;
+
+    // stdin is written from a separate thread: a tool that prints a lot before it has read all of
+    // its input would otherwise deadlock with us
+    let writer = child.stdin.take().map(|mut stdin| {
+        let arguments = tool_call.function.arguments.clone();
+        thread::spawn(move || stdin.write_all(arguments.as_bytes()))
+    });
+
+    // same goes for stdout and stderr: a tool blocked on a full pipe would never exit
+    let stdout_reader = spawn_reader(child.stdout.take());
+    let stderr_reader = spawn_reader(child.stderr.take());
+
+    let status = match timeout {
+        None => child.wait(),
+        Some(timeout) => match wait_timeout(&mut child, timeout)? {
+            Some(status) => Ok(status),
+            None => {
+                // the reader threads are not joined: anything the tool spawned might still hold
+                // the pipes open
+                child.kill().ok();
+                child.wait().ok();
+                return Err(anyhow::anyhow!(
+                    "Tool {} timed out after {} seconds",
+                    tool_call.function.name,
+                    timeout.as_secs_f64()
+                ));
+            }
+        },
+    }
+    .with_context(|| format!("Could not run {}", exec_path.display()))?;
+
+    let stdout = join_reader(stdout_reader).context("Could not read the tool stdout")?;
+    let stderr = join_reader(stderr_reader).context("Could not read the tool stderr")?;
+
+    if let Some(writer) = writer {
+        // a tool is free to exit without reading its input, so a broken pipe is not an error
+        match writer.join() {
+            Ok(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(e).context("Could not write the tool arguments to stdin");
+            }
+            _ => {}
+        }
+    }
+
+    let is_final = status.code() == Some(FINAL_EXIT_CODE);
+    if !status.success() && !is_final {
+        return Err(anyhow::anyhow!(
+            "Tool {} failed with {}; stderr was: {}",
+            tool_call.function.name,
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        ));
+    }
+
+    let output = match tool.definition.output {
+        ToolOutput::Text | ToolOutput::Messages => {
+            String::from_utf8(stdout).context("Tool output is not valid UTF-8")?
+        }
+        ToolOutput::Base64 => BASE64.encode(stdout),
+    };
+    Ok(ToolResult { output, is_final })
+}
+
+/// Checks that the arguments are valid JSON, and that they match the basic shape of the
+/// `parameters` schema of the tool: an object type, and the required properties. This is not a full
+/// JSON schema validation, it only catches the common mistakes.
+fn validate_arguments(tool: &Tool, arguments: &str) -> Result<()> {
+    let arguments: serde_json::Value =
+        serde_json::from_str(arguments).context("Arguments are not valid JSON")?;
+
+    let Some(schema) = &tool.definition.function.parameters else {
+        return Ok(());
+    };
+
+    if schema.get("type").and_then(|t| t.as_str()) == Some("object") {
+        let object = arguments
+            .as_object()
+            .context("Arguments are not a JSON object")?;
+
+        let required = schema.get("required").and_then(|r| r.as_array());
+        for name in required.into_iter().flatten().filter_map(|r| r.as_str()) {
+            if !object.contains_key(name) {
+                return Err(anyhow::anyhow!("Required argument {} is missing", name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the child to exit for at most `timeout`. Returns `None` if it is still running.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().context("Could not wait for the tool")? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> Option<thread::JoinHandle<io::Result<Vec<u8>>>> {
+    pipe.map(|mut pipe| {
+        thread::spawn(move || {
+            let mut buffer = Vec::new();
+            pipe.read_to_end(&mut buffer)?;
+            Ok(buffer)
+        })
+    })
+}
+
+fn join_reader(reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>) -> Result<Vec<u8>> {
+    match reader {
+        Some(reader) => reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Reader thread panicked"))?
+            .map_err(anyhow::Error::from),
+        None => Ok(Vec::new()),
+    }
+}
@@ -0,0 +1,110 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::Message;
+
+/// Writes the conversation to `path` as a Markdown document, for reading and sharing rather than
+/// for loading back (that's what sessions are for).
+pub fn write_transcript(path: &Path, messages: &[Message]) -> Result<()> {
+    fs::write(path, render(messages)).with_context(|| format!("Could not write {}", path.display()))
+}
+
+fn render(messages: &[Message]) -> String {
+    let mut document = String::from("# Transcript\n");
+
+    for message in messages {
+        match &message.tool_call_id {
+            Some(id) if message.role == "tool" => {
+                document.push_str(&format!("\n## Tool result (`{}`)\n\n", id));
+                document.push_str(&fenced("", message.content.as_deref().unwrap_or_default()));
+            }
+            _ => {
+                document.push_str(&format!("\n## {}\n", heading(&message.role)));
+                if let Some(content) = message.content.as_deref()
+                    && !content.is_empty()
+                {
+                    document.push_str(&format!("\n{}\n", content.trim_end()));
+                }
+            }
+        }
+
+        for url in &message.images {
+            // embedded images would only be a wall of base64
+            if url.starts_with("data:") {
+                document.push_str("\n*(image)*\n");
+            } else {
+                document.push_str(&format!("\n![image]({})\n", url));
+            }
+        }
+
+        for tool_call in message.tool_calls.iter().flatten() {
+            document.push_str(&format!(
+                "\nTool call `{}` (`{}`):\n\n",
+                tool_call.function.name, tool_call.id
+            ));
+            // the model may have generated invalid JSON, which is shown as it is
+            let arguments =
+                serde_json::from_str::<serde_json::Value>(&tool_call.function.arguments)
+                    .ok()
+                    .and_then(|value| serde_json::to_string_pretty(&value).ok())
+                    .unwrap_or_else(|| tool_call.function.arguments.clone());
+            document.push_str(&fenced("json", &arguments));
+        }
+    }
+    document
+}
+
+/// "assistant" -> "Assistant"
+fn heading(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Returns a code fence for `text`: one that is longer than any run of backticks in it, since such
+/// a run would end a shorter fence.
+pub fn fence_for(text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Wraps `text` in a code block.
+fn fenced(language: &str, text: &str) -> String {
+    let fence = fence_for(text);
+    format!(
+        "{}{}\n{}\n{}\n",
+        fence,
+        language,
+        text.trim_end_matches('\n'),
+        fence
+    )
+}
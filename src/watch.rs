@@ -0,0 +1,84 @@
+/*
+rlm - core LLM command line interface of rapidllm.
+Copyright (C) 2025 Fedir Kovalov
+
+This program is free software: you can redistribute it
+and/or modify it under the terms of the GNU Lesser
+General Public License as published by the Free
+Software Foundation, either version 3 of the License,
+or (at your option) any later version.
+
+This program is distributed in the hope that it will
+be useful, but WITHOUT ANY WARRANTY; without even the
+implied warranty of MERCHANTABILITY or FITNESS FOR A
+PARTICULAR PURPOSE. See the GNU General Public License
+for more details.
+
+You should have received a copy of the GNU Lesser
+General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long the files have to stay unchanged before a change counts. Editors tend to save in
+/// several steps (write a temporary file, rename it, touch it), which would otherwise be several
+/// changes.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Watches the files of --watch for changes.
+pub struct Watcher {
+    _watcher: notify::RecommendedWatcher, // stops watching when dropped
+    changes: mpsc::UnboundedReceiver<()>,
+}
+
+/// Starts watching the files. Their directories are watched rather than the files themselves,
+/// since editors that save by replacing the file would make a watch on the file itself go stale.
+pub fn watch(paths: &[PathBuf]) -> Result<Watcher> {
+    let mut files = Vec::new();
+    for path in paths {
+        files.push(
+            path.canonicalize()
+                .with_context(|| format!("Could not watch {}", path.display()))?,
+        );
+    }
+
+    let (sender, changes) = mpsc::unbounded_channel();
+    let targets = files.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // reading the files (as every run does) is no change
+        if let Ok(event) = event
+            && matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            )
+            && event.paths.iter().any(|path| targets.contains(path))
+        {
+            sender.send(()).ok();
+        }
+    })
+    .context("Could not watch files")?;
+
+    for file in &files {
+        let dir = file.parent().unwrap_or(file);
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Could not watch {}", dir.display()))?;
+    }
+    Ok(Watcher {
+        _watcher: watcher,
+        changes,
+    })
+}
+
+impl Watcher {
+    /// Waits until one of the files changed, and then until they stopped changing.
+    pub async fn changed(&mut self) {
+        self.changes.recv().await;
+        while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, self.changes.recv()).await {}
+    }
+}